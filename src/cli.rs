@@ -1,5 +1,5 @@
 use crate::commands::root_directory;
-use clap::{App, AppSettings, Arg, ColorChoice};
+use clap::{App, AppSettings, Arg, ArgGroup, ColorChoice};
 use std::collections::BTreeSet;
 
 mod commands;
@@ -10,8 +10,19 @@ extern crate lazy_static;
 const LIST_INSTALLED_PACKAGES_COMMAND: &str = "installed";
 const SEARCH_AVAILABLE_PACKAGES_COMMAND: &str = "search";
 const INSTALL_PACKAGES_COMMAND: &str = "install";
+const REINSTALL_PACKAGES_COMMAND: &str = "reinstall";
 const UPDATE_PACKAGES_COMMAND: &str = "update";
+const LIST_OUTDATED_PACKAGES_COMMAND: &str = "outdated";
+const RECOVER_COMMAND: &str = "recover";
+const MARK_PACKAGES_COMMAND: &str = "mark";
+const HOLD_PACKAGES_COMMAND: &str = "hold";
+const UNHOLD_PACKAGES_COMMAND: &str = "unhold";
+const AUTOREMOVE_COMMAND: &str = "autoremove";
+const WHY_COMMAND: &str = "why";
 const LIST_DEPENDENCIES_COMMAND: &str = "dependencies";
+const LIST_REVERSE_DEPENDENCIES_COMMAND: &str = "rdepends";
+const PACKAGE_INFO_COMMAND: &str = "info";
+const LIST_GROUPS_COMMAND: &str = "groups";
 
 fn main() {
     let _ = ansi_term::enable_ansi_support();
@@ -25,6 +36,23 @@ fn main() {
                 .long("no-prompt")
                 .help("Disable confirmation prompts."),
         )
+        .arg(
+            Arg::new("break-lock")
+                .long("break-lock")
+                .global(true)
+                .help("Remove the lock on the root directory left by another process."),
+        )
+        .arg(
+            Arg::new("env")
+                .long("env")
+                .global(true)
+                .takes_value(true)
+                .possible_values(commands::environment_names())
+                .help(
+                    "Adds the environment package prefix to package names (e.g. gcc), \
+                     and syncs the environment repository instead of mingw64.",
+                ),
+        )
         .subcommand(
             App::new(LIST_INSTALLED_PACKAGES_COMMAND)
                 .about("list installed packages")
@@ -40,7 +68,39 @@ fn main() {
                 .about("search available packages")
                 .arg(
                     Arg::new("term")
-                        .help("The term to look for in the package name")
+                        .help("The term to look for in the package name or description (globs are supported)")
+                        .required(true)
+                        .multiple_values(true),
+                )
+                .arg(
+                    Arg::new("regex")
+                        .long("regex")
+                        .help("Treat the terms as regular expressions"),
+                )
+                .arg(
+                    Arg::new("repo")
+                        .long("repo")
+                        .takes_value(true)
+                        .help("Only show packages from the specified repository"),
+                )
+                .arg(
+                    Arg::new("installed")
+                        .long("installed")
+                        .conflicts_with("not-installed")
+                        .help("Only show installed packages"),
+                )
+                .arg(
+                    Arg::new("not-installed")
+                        .long("not-installed")
+                        .help("Only show packages that are not installed"),
+                ),
+        )
+        .subcommand(
+            App::new(PACKAGE_INFO_COMMAND)
+                .about("show package information")
+                .arg(
+                    Arg::new("name")
+                        .help("The name of the packages")
                         .required(true)
                         .multiple_values(true),
                 ),
@@ -53,6 +113,42 @@ fn main() {
                         .help("The name of the packages to update")
                         .required(true)
                         .multiple_values(true),
+                )
+                .arg(
+                    Arg::new("tree")
+                        .long("tree")
+                        .conflicts_with("dot")
+                        .help("Show the dependencies as a tree"),
+                )
+                .arg(
+                    Arg::new("dot")
+                        .long("dot")
+                        .help("Output a Graphviz graph of the dependencies"),
+                ),
+        )
+        .subcommand(
+            App::new(LIST_REVERSE_DEPENDENCIES_COMMAND)
+                .about("list the packages that depend on the specified packages")
+                .arg(
+                    Arg::new("name")
+                        .help("The name of the packages")
+                        .required(true)
+                        .multiple_values(true),
+                )
+                .arg(
+                    Arg::new("recursive")
+                        .long("recursive")
+                        .help("Include the packages that depend on them indirectly"),
+                ),
+        )
+        .subcommand(
+            App::new(LIST_GROUPS_COMMAND)
+                .about("list the available package groups, or the members of groups")
+                .arg(
+                    Arg::new("name")
+                        .help("The name of the groups")
+                        .required(false)
+                        .multiple_values(true),
                 ),
         )
         .subcommand(
@@ -60,9 +156,45 @@ fn main() {
                 .about("install packages")
                 .arg(
                     Arg::new("name")
-                        .help("The name of the package to install")
+                        .help("The name of the package to install, with an optional version (name=version or name<version), the path of a package archive, or the name of a group")
+                        .required(true)
+                        .multiple_values(true),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .help("Show the packages that would be installed without changing anything"),
+                )
+                .arg(
+                    Arg::new("with-optional")
+                        .long("with-optional")
+                        .help("Also install the optional dependencies of the packages"),
+                )
+                .arg(
+                    Arg::new("needed")
+                        .long("needed")
+                        .conflicts_with("reinstall")
+                        .help("Skip the packages that are already up to date (default)"),
+                )
+                .arg(
+                    Arg::new("reinstall")
+                        .long("reinstall")
+                        .help("Reinstall the packages that are already up to date"),
+                ),
+        )
+        .subcommand(
+            App::new(REINSTALL_PACKAGES_COMMAND)
+                .about("reinstall installed packages, keeping their install reason")
+                .arg(
+                    Arg::new("name")
+                        .help("The name of the packages to reinstall")
                         .required(true)
                         .multiple_values(true),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .help("Show the packages that would be reinstalled without changing anything"),
                 ),
         )
         .subcommand(
@@ -70,11 +202,84 @@ fn main() {
                 .about("update packages")
                 .arg(
                     Arg::new("name")
-                        .help("The name of the packages to update")
+                        .help("The name of the packages to update (use install for a specific version)")
                         .required(false)
                         .multiple_values(true),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .help("Show the packages that would be updated without changing anything"),
+                ),
+        )
+        .subcommand(
+            App::new(LIST_OUTDATED_PACKAGES_COMMAND)
+                .about("list installed packages with a newer version available")
+                .after_help("Exits with status 100 if there are packages to update."),
+        )
+        .subcommand(
+            App::new(WHY_COMMAND)
+                .about("show why installed packages are needed")
+                .arg(
+                    Arg::new("name")
+                        .help("The name of the installed packages")
+                        .required(true)
+                        .multiple_values(true),
+                ),
+        )
+        .subcommand(
+            App::new(MARK_PACKAGES_COMMAND)
+                .about("change the install reason of installed packages")
+                .arg(
+                    Arg::new("name")
+                        .help("The name of the packages")
+                        .required(true)
+                        .multiple_values(true),
+                )
+                .arg(
+                    Arg::new("explicit")
+                        .long("explicit")
+                        .help("Mark the packages as explicitly installed"),
+                )
+                .arg(
+                    Arg::new("dependency")
+                        .long("dependency")
+                        .help("Mark the packages as installed as dependencies"),
+                )
+                .group(
+                    ArgGroup::new("reason")
+                        .args(&["explicit", "dependency"])
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            App::new(HOLD_PACKAGES_COMMAND)
+                .about("keep installed packages at their current version when updating")
+                .arg(
+                    Arg::new("name")
+                        .help("The name of the packages")
+                        .required(true)
+                        .multiple_values(true),
+                ),
+        )
+        .subcommand(
+            App::new(UNHOLD_PACKAGES_COMMAND)
+                .about("let update change held packages again")
+                .arg(
+                    Arg::new("name")
+                        .help("The name of the packages")
+                        .required(true)
+                        .multiple_values(true),
                 ),
         )
+        .subcommand(
+            App::new(AUTOREMOVE_COMMAND)
+                .about("remove dependencies that are not required by any installed package"),
+        )
+        .subcommand(
+            App::new(RECOVER_COMMAND)
+                .about("roll back the changes of an interrupted installation or update"),
+        )
         //.arg(Arg::new("hidden").short('V').hidden(true))
         .setting(AppSettings::ArgRequiredElseHelp)
         .color(ColorChoice::Auto);
@@ -82,6 +287,9 @@ fn main() {
     // .setting(AppSettings::NoBinaryName);
     let matches = app.get_matches();
     let no_prompt = matches.occurrences_of("no-prompt") > 0;
+    let environment = matches.value_of("env");
+    commands::select_environment(environment);
+    let break_lock = matches.occurrences_of("break-lock") > 0;
     if let Some(matches) = matches.subcommand_matches(LIST_INSTALLED_PACKAGES_COMMAND) {
         if let Some(packages) = matches.values_of("package") {
             let packages: BTreeSet<_> = packages.collect();
@@ -93,24 +301,132 @@ fn main() {
     } else if let Some(matches) = matches.subcommand_matches(SEARCH_AVAILABLE_PACKAGES_COMMAND) {
         if let Some(terms) = matches.values_of("term") {
             let terms: BTreeSet<_> = terms.collect();
-            commands::search_available_packages(&root_directory(no_prompt), terms);
+            let options = commands::SearchOptions {
+                regex: matches.occurrences_of("regex") > 0,
+                repository: matches.value_of("repo"),
+                installed: if matches.occurrences_of("installed") > 0 {
+                    Some(true)
+                } else if matches.occurrences_of("not-installed") > 0 {
+                    Some(false)
+                } else {
+                    None
+                },
+            };
+            commands::search_available_packages(&root_directory(no_prompt), terms, options);
+        }
+    } else if let Some(matches) = matches.subcommand_matches(PACKAGE_INFO_COMMAND) {
+        if let Some(names) = matches.values_of("name") {
+            let names: BTreeSet<_> = names.collect();
+            commands::show_package_info(&root_directory(no_prompt), names, environment);
         }
     } else if let Some(matches) = matches.subcommand_matches(LIST_DEPENDENCIES_COMMAND) {
         if let Some(names) = matches.values_of("name") {
             let names: BTreeSet<_> = names.collect();
-            commands::list_dependencies(&root_directory(no_prompt), names, no_prompt);
+            commands::list_dependencies(
+                &root_directory(no_prompt),
+                names,
+                environment,
+                no_prompt,
+                matches.occurrences_of("tree") > 0,
+                matches.occurrences_of("dot") > 0,
+            );
+        }
+    } else if let Some(matches) = matches.subcommand_matches(LIST_REVERSE_DEPENDENCIES_COMMAND) {
+        if let Some(names) = matches.values_of("name") {
+            let names: BTreeSet<_> = names.collect();
+            commands::list_reverse_dependencies(
+                &root_directory(no_prompt),
+                names,
+                environment,
+                matches.occurrences_of("recursive") > 0,
+            );
         }
+    } else if let Some(matches) = matches.subcommand_matches(LIST_GROUPS_COMMAND) {
+        let names: BTreeSet<_> = matches.values_of("name").into_iter().flatten().collect();
+        commands::list_groups(&root_directory(no_prompt), names, environment);
     } else if let Some(matches) = matches.subcommand_matches(INSTALL_PACKAGES_COMMAND) {
         if let Some(names) = matches.values_of("name") {
             let names: BTreeSet<_> = names.collect();
-            commands::install_packages(&root_directory(no_prompt), names, no_prompt);
+            let root_directory = root_directory(no_prompt);
+            let _lock = commands::lock_root_directory(&root_directory, break_lock);
+            commands::install_packages(
+                &root_directory,
+                names,
+                environment,
+                no_prompt,
+                matches.occurrences_of("dry-run") > 0,
+                matches.occurrences_of("with-optional") > 0,
+                matches.occurrences_of("reinstall") > 0,
+            );
+        }
+    } else if let Some(matches) = matches.subcommand_matches(REINSTALL_PACKAGES_COMMAND) {
+        if let Some(names) = matches.values_of("name") {
+            let names: BTreeSet<_> = names.collect();
+            let root_directory = root_directory(no_prompt);
+            let _lock = commands::lock_root_directory(&root_directory, break_lock);
+            commands::reinstall_packages(
+                &root_directory,
+                names,
+                environment,
+                no_prompt,
+                matches.occurrences_of("dry-run") > 0,
+            );
         }
     } else if let Some(matches) = matches.subcommand_matches(UPDATE_PACKAGES_COMMAND) {
+        let dry_run = matches.occurrences_of("dry-run") > 0;
+        let root_directory = root_directory(no_prompt);
+        let _lock = commands::lock_root_directory(&root_directory, break_lock);
         if let Some(names) = matches.values_of("name") {
             let names: BTreeSet<_> = names.collect();
-            commands::update_packages(&root_directory(no_prompt), names, no_prompt);
+            commands::update_packages(&root_directory, names, environment, no_prompt, dry_run);
         } else {
-            commands::update_packages(&root_directory(no_prompt), BTreeSet::new(), no_prompt);
+            commands::update_packages(
+                &root_directory,
+                BTreeSet::new(),
+                environment,
+                no_prompt,
+                dry_run,
+            );
+        }
+    } else if matches
+        .subcommand_matches(LIST_OUTDATED_PACKAGES_COMMAND)
+        .is_some()
+    {
+        commands::list_outdated_packages(&root_directory(no_prompt));
+    } else if let Some(matches) = matches.subcommand_matches(WHY_COMMAND) {
+        if let Some(names) = matches.values_of("name") {
+            let names: BTreeSet<_> = names.collect();
+            commands::explain_packages(&root_directory(no_prompt), names);
+        }
+    } else if let Some(matches) = matches.subcommand_matches(MARK_PACKAGES_COMMAND) {
+        if let Some(names) = matches.values_of("name") {
+            let names: BTreeSet<_> = names.collect();
+            let explicit = matches.occurrences_of("explicit") > 0;
+            let root_directory = root_directory(no_prompt);
+            let _lock = commands::lock_root_directory(&root_directory, break_lock);
+            commands::mark_packages(&root_directory, names, explicit);
+        }
+    } else if let Some(matches) = matches.subcommand_matches(HOLD_PACKAGES_COMMAND) {
+        if let Some(names) = matches.values_of("name") {
+            let names: BTreeSet<_> = names.collect();
+            let root_directory = root_directory(no_prompt);
+            let _lock = commands::lock_root_directory(&root_directory, break_lock);
+            commands::hold_packages(&root_directory, names, true);
+        }
+    } else if let Some(matches) = matches.subcommand_matches(UNHOLD_PACKAGES_COMMAND) {
+        if let Some(names) = matches.values_of("name") {
+            let names: BTreeSet<_> = names.collect();
+            let root_directory = root_directory(no_prompt);
+            let _lock = commands::lock_root_directory(&root_directory, break_lock);
+            commands::hold_packages(&root_directory, names, false);
         }
+    } else if matches.subcommand_matches(AUTOREMOVE_COMMAND).is_some() {
+        let root_directory = root_directory(no_prompt);
+        let _lock = commands::lock_root_directory(&root_directory, break_lock);
+        commands::remove_orphan_packages(&root_directory, no_prompt);
+    } else if matches.subcommand_matches(RECOVER_COMMAND).is_some() {
+        let root_directory = root_directory(no_prompt);
+        let _lock = commands::lock_root_directory(&root_directory, break_lock);
+        commands::recover(&root_directory);
     }
 }
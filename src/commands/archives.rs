@@ -0,0 +1,379 @@
+use crate::commands::available_packages;
+use crate::commands::catalogue::Catalogue;
+use crate::commands::config::Config;
+use crate::commands::errors::{Error, Result};
+use crate::commands::packages::{Metadata, Package};
+use crate::commands::paths;
+use crate::commands::repositories::Repository;
+use crate::commands::utils;
+use crate::commands::utils::Compression;
+use crate::commands::versions;
+use regex::Regex;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+const PACKAGE_EXTENSION: &str = ".pkg.tar.";
+const PACKAGE_INFO: &str = ".PKGINFO";
+
+lazy_static! {
+    static ref LINK: Regex = Regex::new(r#"href="([^"]+\.pkg\.tar\.[a-z]+)""#).unwrap();
+}
+
+/// Version of a package requested on the command line with name=version or name<version.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Requirement<'a> {
+    Exact(&'a str),
+    Below(&'a str),
+}
+
+impl Requirement<'_> {
+    /// The release can be omitted (e.g. gcc=11.2.0 matches 11.2.0-1).
+    pub fn matches(&self, version: &str) -> bool {
+        match self {
+            Self::Exact(it) => versions::compare(version, it) == Ordering::Equal,
+            Self::Below(it) => versions::compare(version, it) == Ordering::Less,
+        }
+    }
+}
+
+/// Splits a name=version or name<version argument into the name and the version requirement.
+pub fn requirement(argument: &str) -> (&str, Option<Requirement<'_>>) {
+    if let Some((name, version)) = argument.split_once('=') {
+        (name, Some(Requirement::Exact(version)))
+    } else if let Some((name, version)) = argument.split_once('<') {
+        (name, Some(Requirement::Below(version)))
+    } else {
+        (argument, None)
+    }
+}
+
+/// Returns true if the argument is the path of a package archive rather than a package name.
+pub fn is_archive_path(argument: &str) -> bool {
+    argument.contains(PACKAGE_EXTENSION) && Path::new(argument).is_file()
+}
+
+/// The archive file name: {name}-{version}-{arch}.pkg.tar.{compression}.
+/// The epoch separator is replaced with ~ like in the repository file names.
+pub fn file_name(package: &Package) -> Option<String> {
+    Some(format!(
+        "{}-{}-{}{}{}",
+        package.name(),
+        package.version.replace(':', "~"),
+        package.arch.as_ref()?,
+        PACKAGE_EXTENSION,
+        package.compression?.extension()
+    ))
+}
+
+/// Parses an archive file name ({name}-{version}-{release}-{arch}.pkg.tar.{compression}).
+/// The repository is deduced from the package prefix, and the dependencies are unknown
+/// until the .PKGINFO file of the archive is read.
+pub fn from_file_name(file_name: &str) -> Option<Package> {
+    let (stem, extension) = file_name.split_once(PACKAGE_EXTENSION)?;
+    let compression = Compression::from_extension(extension)?;
+    let mut parts = stem.rsplitn(4, '-');
+    let arch = parts.next()?;
+    let release = parts.next()?;
+    let version = parts.next()?;
+    let name = parts.next()?;
+    let repository = Repository::environments()
+        .find(|it| name.starts_with(it.package_prefix()))
+        .unwrap_or(&Repository::Msys);
+    Some(Package {
+        repository,
+        names: vec![name.to_string()],
+        version: format!("{}-{}", version.replace('~', ":"), release),
+        compression: Some(compression),
+        arch: Some(arch.to_string()),
+        dependencies: None,
+        optional_dependencies: vec![],
+        metadata: Metadata::default(),
+        installation: None,
+    })
+}
+
+/// Completes a package parsed from an archive file name with the content of the .PKGINFO file
+/// of the archive (provided names, dependencies, description, ...).
+pub fn read_package_info(package: &Package, archive: &[u8]) -> Result<Package> {
+    let uncompressed = package
+        .compression
+        .ok_or(Error::ParseError)?
+        .decompress(archive)?;
+    let mut tar = tar::Archive::new(uncompressed.as_slice());
+    let mut info = String::new();
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.to_string_lossy() == PACKAGE_INFO {
+            entry.read_to_string(&mut info)?;
+            break;
+        }
+    }
+    if info.is_empty() {
+        return Err(Error::ParseError);
+    }
+    let mut package = package.clone();
+    let mut dependencies = Vec::new();
+    let metadata = &mut package.metadata;
+    metadata.compressed_size = Some(archive.len() as u64);
+    // one "key = value" line per value, lists repeat the key
+    for (key, value) in info
+        .lines()
+        .filter(|it| !it.starts_with('#'))
+        .filter_map(|it| it.split_once(" = "))
+    {
+        match key {
+            "pkgname" if value != package.names[0] => return Err(Error::ParseError),
+            "pkgver" => package.version = value.to_string(),
+            "provides" => package.names.push(value.to_string()),
+            "depend" => dependencies.push(value.to_string()),
+            "optdepend" => package.optional_dependencies.push(value.to_string()),
+            "pkgdesc" => metadata.description = Some(value.to_string()),
+            "url" => metadata.homepage = Some(value.to_string()),
+            "license" => metadata.licenses.push(value.to_string()),
+            "size" => metadata.installed_size = value.parse().ok(),
+            "builddate" => metadata.build_date = value.parse().ok(),
+            "packager" => metadata.packager = Some(value.to_string()),
+            "group" => metadata.groups.push(value.to_string()),
+            "pkgbase" => metadata.base = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    package.dependencies = Some(dependencies);
+    Ok(package)
+}
+
+/// Returns the archive from the cache directory (var/local/packages/cache) if it is there.
+pub fn cached_archive(root_directory_path: &Path, package: &Package) -> Option<Vec<u8>> {
+    let file_name = file_name(package)?;
+    fs::read(paths::get_archive_cache_directory_path(root_directory_path).join(file_name)).ok()
+}
+
+/// Saves the archive to the cache directory, so that the version can be installed again later
+/// (e.g. to downgrade after an update).
+pub fn cache_archive(root_directory_path: &Path, package: &Package, archive: &[u8]) -> Result<()> {
+    let file_name = file_name(package).ok_or(Error::ParseError)?;
+    let directory = paths::get_archive_cache_directory_path(root_directory_path);
+    fs::create_dir_all(&directory)?;
+    fs::write(directory.join(file_name), archive)?;
+    Ok(())
+}
+
+/// Returns the packages of the archives in the cache directory.
+fn cached_packages(root_directory_path: &Path) -> Vec<Package> {
+    fs::read_dir(paths::get_archive_cache_directory_path(root_directory_path))
+        .map(|entries| {
+            entries
+                .filter_map(|it| it.ok())
+                .filter_map(|it| from_file_name(&it.file_name().to_string_lossy()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Returns the packages listed on the directory index of an archive mirror, with their url.
+fn mirrored_packages(url: &str) -> Vec<(Package, String)> {
+    let index = match utils::download(url) {
+        Ok(response) => String::from_utf8_lossy(&response.body).to_string(),
+        Err(_) => return vec![],
+    };
+    links(&index)
+        .into_iter()
+        .filter_map(|link| {
+            let file_name = link.rsplit('/').next()?.replace("%7E", "~");
+            let package = from_file_name(&file_name)?;
+            if link.contains("://") {
+                Some((package, link.to_string()))
+            } else {
+                Some((package, format!("{}{}", url, link)))
+            }
+        })
+        .collect()
+}
+
+/// The links to package archives of an html page.
+fn links(html: &str) -> Vec<&str> {
+    LINK.captures_iter(html)
+        .filter_map(|it| it.get(1))
+        .map(|it| it.as_str())
+        .collect()
+}
+
+/// Finds the latest version of a package that satisfies the requirement, among the available
+/// packages, the archives in the cache and the archives of the mirror configured for
+/// its repository (archive=mingw64,https://...).
+/// Archives that are not in the cache are downloaded to the cache first, so that their
+/// .PKGINFO file can be read.
+pub fn find(
+    root_directory_path: &Path,
+    name: &str,
+    requirement: &Requirement,
+    environment: Option<&'static Repository>,
+    available_packages: &Catalogue,
+    config: &Config,
+) -> Option<Package> {
+    let mut urls = BTreeMap::new();
+    let mut candidates: BTreeSet<Package> = available_packages.iter().cloned().collect();
+    for package in cached_packages(root_directory_path) {
+        candidates.insert(package);
+    }
+    for (repository, url) in &config.archives {
+        if Repository::from(repository).is_none() {
+            continue;
+        }
+        for (package, url) in mirrored_packages(url) {
+            if !candidates.contains(&package) {
+                urls.insert(file_name(&package)?, url);
+                candidates.insert(package);
+            }
+        }
+    }
+    let candidates = Catalogue::from(candidates);
+    let latest = available_packages::find(name, environment, &candidates)?;
+    let package = candidates
+        .providers(latest.name())
+        .filter(|it| it.name() == latest.name() && it.repository == latest.repository)
+        .find(|it| requirement.matches(&it.version))?;
+    if package.dependencies.is_some() {
+        // from the available packages
+        return Some(package.clone());
+    }
+    let archive = match cached_archive(root_directory_path, package) {
+        Some(archive) => archive,
+        None => {
+            let archive = utils::download(urls.get(&file_name(package)?)?).ok()?.body;
+            cache_archive(root_directory_path, package, &archive).ok()?;
+            archive
+        }
+    };
+    read_package_info(package, &archive).ok()
+}
+
+/// Reads a package archive file, and copies it to the cache so that the installer finds it.
+pub fn read_archive_file(root_directory_path: &Path, path: &Path) -> Result<Package> {
+    let package = path
+        .file_name()
+        .and_then(|it| from_file_name(&it.to_string_lossy()))
+        .ok_or(Error::ParseError)?;
+    let archive = fs::read(path)?;
+    let package = read_package_info(&package, &archive)?;
+    cache_archive(root_directory_path, &package, &archive)?;
+    Ok(package)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    lazy_static! {
+        static ref DATA_DIR: PathBuf = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("data");
+    }
+
+    fn archive(info: &str) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(info.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, PACKAGE_INFO, info.as_bytes())
+            .unwrap();
+        zstd::encode_all(builder.into_inner().unwrap().as_slice(), 0).unwrap()
+    }
+
+    #[test]
+    fn requirements() {
+        assert_eq!(requirement("gcc"), ("gcc", None));
+        assert_eq!(
+            requirement("gcc=11.2.0"),
+            ("gcc", Some(Requirement::Exact("11.2.0")))
+        );
+        assert_eq!(
+            requirement("mingw64/gcc<12"),
+            ("mingw64/gcc", Some(Requirement::Below("12")))
+        );
+        assert!(Requirement::Exact("11.2.0").matches("11.2.0-3"));
+        assert!(!Requirement::Exact("11.2.0-1").matches("11.2.0-3"));
+        assert!(Requirement::Below("12").matches("11.2.0-3"));
+        assert!(!Requirement::Below("12").matches("12.1.0-1"));
+    }
+
+    #[test]
+    fn file_names() {
+        let package =
+            from_file_name("mingw-w64-x86_64-python-pip-1~21.3-2-any.pkg.tar.zst").unwrap();
+        assert_eq!(package.repository, &Repository::Mingw64);
+        assert_eq!(package.name(), "mingw-w64-x86_64-python-pip");
+        assert_eq!(package.version, "1:21.3-2");
+        assert_eq!(package.arch.as_deref(), Some("any"));
+        assert_eq!(
+            file_name(&package).unwrap(),
+            "mingw-w64-x86_64-python-pip-1~21.3-2-any.pkg.tar.zst"
+        );
+        let package = from_file_name("bash-5.1.016-1-x86_64.pkg.tar.xz").unwrap();
+        assert_eq!(package.repository, &Repository::Msys);
+        assert_eq!(package.version, "5.1.016-1");
+        assert!(from_file_name("bash-5.1.016-1-x86_64.pkg.tar.zst.sig").is_none());
+        assert!(from_file_name("bash.tar.zst").is_none());
+        assert_eq!(
+            links(
+                r#"<a href="bash-5.1-1-x86_64.pkg.tar.zst">bash</a><a href="bash-5.1-1-x86_64.pkg.tar.zst.sig">sig</a>"#
+            ),
+            vec!["bash-5.1-1-x86_64.pkg.tar.zst"]
+        );
+    }
+
+    #[test]
+    fn package_info() {
+        let root_directory_path = DATA_DIR.join("tmp_archives");
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+        fs::create_dir_all(&root_directory_path).unwrap();
+        let path = root_directory_path.join("mingw-w64-x86_64-gcc-11.2.0-1-any.pkg.tar.zst");
+        fs::write(
+            &path,
+            archive(
+                "# Generated by makepkg\npkgname = mingw-w64-x86_64-gcc\npkgver = 11.2.0-1\n\
+                 pkgdesc = GNU Compiler Collection\nprovides = mingw-w64-x86_64-cc\n\
+                 depend = mingw-w64-x86_64-binutils\ndepend = mingw-w64-x86_64-zlib\n\
+                 optdepend = mingw-w64-x86_64-gdb: debugger\nsize = 1024\n",
+            ),
+        )
+        .unwrap();
+        assert!(is_archive_path(&path.to_string_lossy()));
+        let package = read_archive_file(&root_directory_path, &path).unwrap();
+        assert_eq!(
+            package.names,
+            ["mingw-w64-x86_64-gcc", "mingw-w64-x86_64-cc"]
+        );
+        assert_eq!(
+            package.dependencies.as_ref().unwrap(),
+            &["mingw-w64-x86_64-binutils", "mingw-w64-x86_64-zlib"]
+        );
+        assert_eq!(package.optional_dependencies.len(), 1);
+        assert_eq!(package.metadata.installed_size, Some(1024));
+        assert!(cached_archive(&root_directory_path, &package).is_some());
+
+        // the cached archive is found by find with a version requirement
+        let config = Config::default();
+        let available = Catalogue::from(BTreeSet::new());
+        let find = |name: &str| {
+            let (name, requirement) = requirement(name);
+            super::find(
+                &root_directory_path,
+                name,
+                &requirement.unwrap(),
+                None,
+                &available,
+                &config,
+            )
+        };
+        let found = find("gcc<12").unwrap();
+        assert_eq!(found.version, "11.2.0-1");
+        assert!(found.dependencies.is_some());
+        assert!(find("gcc=11.1.0").is_none());
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+    }
+}
@@ -4,35 +4,67 @@ use std::path::Path;
 
 use ansi_term::Color;
 
+use crate::commands::catalogue::Catalogue;
 use crate::commands::errors::Result;
+use crate::commands::format::{Header, FORMAT_VERSION};
+use crate::commands::lock;
 use crate::commands::packages::{Package, Packages};
 use crate::commands::repositories::{Repository, RepositoryVersion};
 use crate::commands::utils::{file_was_updated_recently, ETag};
+use crate::commands::versions;
+use std::cmp::Ordering;
 use std::fs;
 use std::fs::File;
-use std::process;
+
+/// Same as get_packages, but returns the packages indexed by name.
+pub fn get_catalogue(available_packages_file: &Path) -> Catalogue {
+    Catalogue::from(get_packages(available_packages_file))
+}
 
 /// Checks if the cached list of available packages is up to date and updates it if necessary,
-/// and then returns a set of packages.
+/// and then returns the packages of the enabled repositories (msys and the selected environment).
+/// The cache keeps the packages of the other repositories that were synced before.
 pub fn get_packages(available_packages_file: &Path) -> BTreeSet<Package> {
-    if file_was_updated_recently(available_packages_file) {
+    sync_packages(available_packages_file)
+        .into_iter()
+        .filter(|it| Repository::enabled().contains(&it.repository))
+        .collect()
+}
+
+fn sync_packages(available_packages_file: &Path) -> BTreeSet<Package> {
+    // caches written with an older format are converted, and synced if they can't be read
+    if let Some(header) =
+        get_header_from_file(available_packages_file).filter(|it| it.version < FORMAT_VERSION)
+    {
+        migrate_packages(available_packages_file, &header);
+    }
+    let outdated_format = get_header_from_file(available_packages_file)
+        .map(|it| it.version < FORMAT_VERSION)
+        .unwrap_or(true);
+    let cache_versions = if outdated_format {
+        BTreeMap::new()
+    } else {
+        get_repository_versions_from_file(available_packages_file)
+    };
+    // a repository that was never synced (e.g. a newly selected environment) or that was migrated
+    // is always synced
+    let complete = Repository::enabled().iter().all(|it| {
+        cache_versions
+            .get(it)
+            .map(|version| !version.etag.value.is_empty())
+            .unwrap_or(false)
+    });
+    if complete && file_was_updated_recently(available_packages_file) {
         match Packages::get_packages_from_file(available_packages_file) {
             Ok(packages) => return packages,
             Err(_) => {}
         }
     }
-    let cache_versions = get_repository_versions_from_file(available_packages_file);
     let repositories_to_sync: Vec<&Repository> = Repository::enabled()
         .iter()
         .map(|it| *it)
-        .filter(|&repository| match cache_versions.get(repository) {
-            Some(cache_version) => match repository.remote_etag() {
-                // sync if the etag have changed
-                Ok(ref repo_etag) => repo_etag != &cache_version.etag,
-                // we can't sync if we can't even get the etag
-                Err(_) => false,
-            },
-            None => true, // sync if we don't have an etag (new repo or cache is missing)
+        .filter(|&repository| {
+            needs_sync(cache_versions.get(repository), || repository.remote_etag())
         })
         .collect();
     if repositories_to_sync.is_empty() {
@@ -115,7 +147,7 @@ pub fn get_packages(available_packages_file: &Path) -> BTreeSet<Package> {
                     Err(_) => {
                         // If we can't read the cache then we can't recover
                         println!("{}", Color::Red.paint("Aborting"));
-                        process::exit(1);
+                        lock::exit(1);
                     }
                 }
             }
@@ -169,24 +201,23 @@ pub fn get_packages(available_packages_file: &Path) -> BTreeSet<Package> {
                                 let version = &it.version.clone();
                                 // add newly fetched packages
                                 full_list.push(it);
+                                package_map.remove(repository);
                                 Some((repository, version.clone()))
                             }
-                            None => {
-                                // add packages from the cache
-                                if let Some(values) = package_map.remove(repository) {
-                                    if let Some(cache_version) = cache_versions.get(repository) {
-                                        full_list
-                                            .push(Packages::create(cache_version.clone(), values))
-                                    }
-                                }
-                                None
-                            }
+                            None => None,
                         }
                     })
                     .collect();
+                // add packages from the cache, including those of the repositories that are not
+                // enabled
+                for (repository, values) in package_map {
+                    if let Some(cache_version) = cache_versions.get(repository) {
+                        full_list.push(Packages::create(cache_version.clone(), values))
+                    }
+                }
                 if successful_syncs.is_empty() {
                     // nothing to save
-                    package_map.into_iter().flat_map(|it| it.1).collect()
+                    full_list.into_iter().flat_map(|it| it.list).collect()
                 } else {
                     // save the new package list
                     save_and_return_packages(available_packages_file, full_list)
@@ -200,11 +231,96 @@ pub fn get_packages(available_packages_file: &Path) -> BTreeSet<Package> {
     }
 }
 
-pub fn latest_version<'a>(name: &str, packages: &'a BTreeSet<Package>) -> Option<&'a Package> {
+/// Returns the installed packages that have a newer version available in the same repository,
+/// together with that newer version.
+pub fn upgrades<'a>(
+    installed_packages: &'a Catalogue,
+    packages: &'a Catalogue,
+) -> Vec<(&'a Package, &'a Package)> {
+    installed_packages
+        .iter()
+        .filter_map(|installed| {
+            upgrade(installed, packages).map(|available| (installed, available))
+        })
+        .collect()
+}
+
+/// Returns the newest version of an installed package if it is newer than the installed version.
+pub fn upgrade<'a>(installed: &Package, packages: &'a Catalogue) -> Option<&'a Package> {
     packages
+        .providers(installed.name())
+        .find(|&it| it.repository == installed.repository && it.name() == installed.name())
+        .filter(|&it| versions::compare(&it.version, &installed.version) == Ordering::Greater)
+}
+
+/// Finds the latest version of a package from a name typed by the user.
+/// The name can be qualified with a repository (e.g. ucrt64/gcc), and the repository package prefix
+/// (e.g. mingw-w64-ucrt-x86_64-) can be omitted.
+/// Unqualified names are looked up with the environment prefix first and then as is,
+/// or, if no environment is specified, as is first and then with the default environment prefix.
+pub fn find<'a>(
+    name: &str,
+    environment: Option<&'static Repository>,
+    packages: &'a Catalogue,
+) -> Option<&'a Package> {
+    if let Some((repository, name)) = name.split_once('/') {
+        let repository = Repository::from(repository)?;
+        return packages.latest_in_repository(&repository.qualified_name(name), repository);
+    }
+    match environment {
+        Some(environment) => packages
+            .latest_in_repository(&environment.qualified_name(name), environment)
+            .or_else(|| packages.latest_version(name)),
+        None => packages.latest_version(name).or_else(|| {
+            let environment = Repository::default_environment();
+            packages.latest_in_repository(&environment.qualified_name(name), environment)
+        }),
+    }
+}
+
+/// Returns true if the repository has to be synced: if it isn't in the cache, if its etag
+/// changed, or if the cache was migrated from an older format (the etag is then empty).
+fn needs_sync(
+    cache_version: Option<&RepositoryVersion>,
+    remote_etag: impl FnOnce() -> Result<ETag>,
+) -> bool {
+    match cache_version {
+        Some(cache_version) => match remote_etag() {
+            // sync if the etag have changed
+            Ok(ref repo_etag) => repo_etag != &cache_version.etag,
+            // we can't sync if we can't even get the etag
+            Err(_) => false,
+        },
+        None => true, // sync if we don't have an etag (new repo or cache is missing)
+    }
+}
+
+/// Rewrites a cache written with an older format with the current format.
+/// The etags are dropped, so that the repositories are synced again to fill the fields that the
+/// older format didn't have, and the packages are kept in case the sync fails.
+fn migrate_packages(available_packages_file: &Path, header: &Header) -> bool {
+    let packages = match Packages::get_packages_from_file(available_packages_file) {
+        Ok(packages) => packages,
+        Err(_) => return false,
+    };
+    let repository_packages: Vec<_> = header
+        .repositories
         .iter()
-        .filter(|&it| it.matches(name))
-        .max_by_key(|&it| &it.version)
+        .map(|version| {
+            Packages::create(
+                RepositoryVersion {
+                    repository: version.repository,
+                    etag: ETag::from(""),
+                },
+                packages
+                    .iter()
+                    .filter(|it| it.repository == version.repository)
+                    .cloned()
+                    .collect(),
+            )
+        })
+        .collect();
+    save_packages(available_packages_file, &repository_packages).is_ok()
 }
 
 fn save_and_return_packages(
@@ -233,13 +349,14 @@ fn save_packages(
     // build content before opening the file to minimize the time the file is only partially written
     let mut data = Vec::with_capacity(131_072);
     let mut encoder = zstd::Encoder::new(&mut data, zstd::DEFAULT_COMPRESSION_LEVEL)?;
-    // header: repo1_name etag1 repo2_name etag2 ...
-    let mut header = repository_packages
-        .iter()
-        .map(|it| format!("{} {}", it.version.repository.name(), &it.version.etag))
-        .collect::<Vec<_>>()
-        .join(" ");
-    header += "\n";
+    // header: format version and the etag of each repository
+    let header = Header::create(
+        repository_packages
+            .iter()
+            .map(|it| it.version.clone())
+            .collect(),
+    );
+    let header = String::from(&header) + "\n";
     encoder.write_all(&header.as_bytes())?;
     // for each repo, add 1 line for each package
     for repo in repository_packages {
@@ -269,7 +386,7 @@ fn get_packages_from_repositories(available_packages_file: &Path) -> BTreeSet<Pa
                             repository.name()
                         ))
                     );
-                    process::exit(1);
+                    lock::exit(1);
                 }
             }
         })
@@ -284,7 +401,7 @@ fn get_packages_from_repositories(available_packages_file: &Path) -> BTreeSet<Pa
                 "{}",
                 Color::Red.paint("Failed to create {} package cache.\nAborting.")
             );
-            process::exit(1);
+            lock::exit(1);
         }
     }
 }
@@ -292,36 +409,35 @@ fn get_packages_from_repositories(available_packages_file: &Path) -> BTreeSet<Pa
 fn get_repository_versions_from_file(
     available_packages_file: &Path,
 ) -> BTreeMap<&Repository, RepositoryVersion> {
+    get_header_from_file(available_packages_file)
+        .map(|header| {
+            header
+                .repositories
+                .into_iter()
+                .map(|it| (it.repository, it))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn get_header_from_file(available_packages_file: &Path) -> Option<Header> {
     File::open(available_packages_file)
         .and_then(|file| zstd::Decoder::new(file))
         .map(|decoder| BufReader::new(decoder))
         .ok()
         .and_then(|reader| {
             if let Some(Ok(first_line)) = reader.lines().next() {
-                let cols: Vec<&str> = first_line.split(' ').collect();
-                cols.chunks(2)
-                    .map(|it| {
-                        Repository::from(&it[0]).map(|repo| {
-                            (
-                                repo,
-                                RepositoryVersion {
-                                    repository: repo,
-                                    etag: ETag::from(it[1]),
-                                },
-                            )
-                        })
-                    })
-                    .collect()
+                Some(Header::parse(&first_line))
             } else {
                 None
             }
         })
-        .unwrap_or(BTreeMap::new())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::commands::errors::Error;
     use std::convert::TryFrom;
     use std::path::PathBuf;
 
@@ -364,6 +480,8 @@ mod tests {
         assert_eq!(versions.len(), 1);
         assert_eq!(versions.get(&Repository::Mingw64).unwrap().etag.value, "3");
         read_repository_versions_from_file_at("available_packages_file1.zst");
+        let header = get_header_from_file(&DATA_DIR.join("available_packages_file1.zst"));
+        assert_eq!(header.unwrap().version, 1);
     }
 
     fn read_repository_versions_from_file_at(filename: &str) {
@@ -374,6 +492,30 @@ mod tests {
         assert_eq!(versions.get(&Repository::Mingw64).unwrap().etag.value, "2");
     }
 
+    #[test]
+    fn migrate_packages_file() {
+        let path = DATA_DIR.join("tmp_migrate.zst");
+        fs::copy(DATA_DIR.join("available_packages_file1.zst"), &path).unwrap();
+        let header = get_header_from_file(&path).unwrap();
+        assert!(migrate_packages(&path, &header));
+        assert_eq!(get_header_from_file(&path).unwrap().version, FORMAT_VERSION);
+        read_packages_from_file_at("tmp_migrate.zst");
+        // the repositories are synced again even though their etags didn't change
+        let versions = get_repository_versions_from_file(&path);
+        assert_eq!(versions.len(), 2);
+        for version in versions.values() {
+            assert!(needs_sync(Some(version), || Ok(ETag::from("1"))));
+            assert!(!needs_sync(Some(version), || Err(Error::ParseError)));
+        }
+        let version = RepositoryVersion {
+            repository: &Repository::Msys,
+            etag: ETag::from("1"),
+        };
+        assert!(!needs_sync(Some(&version), || Ok(ETag::from("1"))));
+        assert!(needs_sync(None, || Ok(ETag::from("1"))));
+        fs::remove_file(&path).unwrap();
+    }
+
     #[test]
     fn write_packages_to_file() {
         rm_rf::ensure_removed(&DATA_DIR.join("tmp")).unwrap();
@@ -408,6 +550,10 @@ mod tests {
         ];
         assert!(save_packages(&DATA_DIR.join("tmp"), &packages).is_ok());
         let _ = Packages::get_packages_from_file(&DATA_DIR.join("tmp")).unwrap();
+        assert_eq!(
+            get_header_from_file(&DATA_DIR.join("tmp")).unwrap().version,
+            FORMAT_VERSION
+        );
         read_repository_versions_from_file_at("tmp");
         read_packages_from_file_at("tmp");
     }
@@ -415,14 +561,92 @@ mod tests {
     #[test]
     fn latest_version() {
         let path = DATA_DIR.join("available_packages_file3.zst");
-        let packages = Packages::get_packages_from_file(&path).unwrap();
-        let latest = super::latest_version("name", &packages);
+        let packages = Catalogue::from(Packages::get_packages_from_file(&path).unwrap());
+        let latest = packages.latest_version("name");
         assert!(latest.is_some());
         assert_eq!("1.2", latest.unwrap().version.as_str());
-        let latest = super::latest_version("other", &packages);
+        let latest = packages.latest_version("other");
         assert!(latest.is_some());
         assert_eq!("0.9", latest.unwrap().version.as_str());
-        let latest = super::latest_version("not_there", &packages);
+        let latest = packages.latest_version("not_there");
         assert!(latest.is_none());
     }
+
+    #[test]
+    fn upgrades() {
+        let available_packages: BTreeSet<_> = [
+            "msys\tbash\t5.1.016-1",
+            "msys\tbash\t5.1.008-1",
+            "msys\tgzip\t1.10-1",
+            "msys\tzlib\t1.2.11-1",
+            "mingw64\tzlib\t1.3-1",
+        ]
+        .iter()
+        .map(|&it| Package::try_from(it).unwrap())
+        .collect();
+        let installed_packages: BTreeSet<_> = [
+            "msys\tbash\t5.1.008-1",
+            "msys\tgzip\t1.9-1",
+            "msys\tzlib\t1.2.11-1",
+            "msys\tlocal\t1.0-1",
+        ]
+        .iter()
+        .map(|&it| Package::try_from(it).unwrap())
+        .collect();
+        let installed_packages = Catalogue::from(installed_packages);
+        let available_packages = Catalogue::from(available_packages);
+        let upgrades: Vec<_> = super::upgrades(&installed_packages, &available_packages)
+            .into_iter()
+            .map(|(installed, available)| {
+                format!(
+                    "{} {} {}",
+                    installed.name(),
+                    installed.version,
+                    available.version
+                )
+            })
+            .collect();
+        assert_eq!(
+            upgrades,
+            vec!["bash 5.1.008-1 5.1.016-1", "gzip 1.9-1 1.10-1"]
+        );
+    }
+
+    #[test]
+    fn find() {
+        let packages: BTreeSet<_> = [
+            "msys\tgcc\t11.1",
+            "msys\tbash\t5.1",
+            "mingw64\tmingw-w64-x86_64-gcc\t11.2",
+            "mingw64\tmingw-w64-x86_64-zlib\t1.2",
+            "ucrt64\tmingw-w64-ucrt-x86_64-gcc\t11.3",
+        ]
+        .iter()
+        .map(|&it| Package::try_from(it).unwrap())
+        .collect();
+        let packages = Catalogue::from(packages);
+        let find = |name: &str, environment: Option<&'static Repository>| {
+            super::find(name, environment, &packages).map(|it| it.name().to_string())
+        };
+        assert_eq!(find("gcc", None).unwrap(), "gcc");
+        assert_eq!(
+            find("gcc", Some(&Repository::Ucrt64)).unwrap(),
+            "mingw-w64-ucrt-x86_64-gcc"
+        );
+        assert_eq!(
+            find("gcc", Some(&Repository::Mingw64)).unwrap(),
+            "mingw-w64-x86_64-gcc"
+        );
+        assert_eq!(find("mingw64/gcc", None).unwrap(), "mingw-w64-x86_64-gcc");
+        assert_eq!(
+            find("ucrt64/mingw-w64-ucrt-x86_64-gcc", None).unwrap(),
+            "mingw-w64-ucrt-x86_64-gcc"
+        );
+        assert_eq!(find("msys/gcc", None).unwrap(), "gcc");
+        assert_eq!(find("bash", Some(&Repository::Ucrt64)).unwrap(), "bash");
+        assert_eq!(find("zlib", None).unwrap(), "mingw-w64-x86_64-zlib");
+        assert!(find("zlib", Some(&Repository::Ucrt64)).is_none());
+        assert!(find("ucrt64/zlib", None).is_none());
+        assert!(find("unknown/gcc", None).is_none());
+    }
 }
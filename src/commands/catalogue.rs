@@ -0,0 +1,305 @@
+use crate::commands::packages::Package;
+use crate::commands::repositories::Repository;
+use crate::commands::versions;
+use std::collections::{BTreeMap, BTreeSet};
+use std::slice::Iter;
+
+/// Set of packages indexed by name and by provided name, so that looking up a dependency
+/// doesn't need to go through every package.
+pub struct Catalogue {
+    /// Sorted like a BTreeSet<Package>.
+    packages: Vec<Package>,
+    /// Indices of the packages with each name (or providing it), grouped by repository,
+    /// latest version first.
+    names: BTreeMap<String, BTreeMap<&'static Repository, Vec<usize>>>,
+    /// Indices of the packages of each group (%GROUPS%).
+    groups: BTreeMap<String, Vec<usize>>,
+}
+
+impl From<BTreeSet<Package>> for Catalogue {
+    fn from(packages: BTreeSet<Package>) -> Self {
+        let packages: Vec<Package> = packages.into_iter().collect();
+        let mut names: BTreeMap<String, BTreeMap<&'static Repository, Vec<usize>>> =
+            BTreeMap::new();
+        let mut groups: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+        for (index, package) in packages.iter().enumerate() {
+            for group in &package.metadata.groups {
+                groups.entry(group.clone()).or_default().push(index);
+            }
+            for name in &package.names {
+                names
+                    .entry(name.clone())
+                    .or_default()
+                    .entry(package.repository)
+                    .or_default()
+                    .push(index);
+            }
+        }
+        for indices in names.values_mut().flat_map(|it| it.values_mut()) {
+            indices.sort_by(|&a, &b| versions::compare(&packages[b].version, &packages[a].version));
+        }
+        Self {
+            packages,
+            names,
+            groups,
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a Catalogue {
+    type Item = &'a Package;
+    type IntoIter = Iter<'a, Package>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.packages.iter()
+    }
+}
+
+impl Catalogue {
+    pub fn iter(&self) -> Iter<'_, Package> {
+        self.packages.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.packages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packages.is_empty()
+    }
+
+    /// Returns the package with the same name and version.
+    pub fn get(&self, package: &Package) -> Option<&Package> {
+        self.packages
+            .binary_search(package)
+            .ok()
+            .map(|index| &self.packages[index])
+    }
+
+    pub fn contains(&self, package: &Package) -> bool {
+        self.packages.binary_search(package).is_ok()
+    }
+
+    /// Adds a package, replacing the package with the same name and version if there is one.
+    pub fn insert(&mut self, package: Package) {
+        let index = match self.packages.binary_search(&package) {
+            Ok(index) => {
+                let replaced = std::mem::replace(&mut self.packages[index], package);
+                self.unindex(&replaced, index);
+                index
+            }
+            Err(index) => {
+                self.packages.insert(index, package);
+                // the packages after the new one moved up by one
+                for indices in self
+                    .names
+                    .values_mut()
+                    .flat_map(|it| it.values_mut())
+                    .chain(self.groups.values_mut())
+                {
+                    indices
+                        .iter_mut()
+                        .filter(|it| **it >= index)
+                        .for_each(|it| *it += 1);
+                }
+                index
+            }
+        };
+        let package = &self.packages[index];
+        for group in &package.metadata.groups {
+            let indices = self.groups.entry(group.clone()).or_default();
+            let position = indices.binary_search(&index).unwrap_or_else(|it| it);
+            indices.insert(position, index);
+        }
+        for name in &package.names {
+            let indices = self
+                .names
+                .entry(name.clone())
+                .or_default()
+                .entry(package.repository)
+                .or_default();
+            // latest version first
+            let position = indices
+                .iter()
+                .position(|&it| {
+                    versions::compare(&self.packages[it].version, &package.version)
+                        == std::cmp::Ordering::Less
+                })
+                .unwrap_or(indices.len());
+            indices.insert(position, index);
+        }
+    }
+
+    /// Removes the index of a package that was replaced from the name and group maps.
+    fn unindex(&mut self, package: &Package, index: usize) {
+        for group in &package.metadata.groups {
+            if let Some(indices) = self.groups.get_mut(group) {
+                indices.retain(|&it| it != index);
+                if indices.is_empty() {
+                    self.groups.remove(group);
+                }
+            }
+        }
+        for name in &package.names {
+            if let Some(repositories) = self.names.get_mut(name) {
+                if let Some(indices) = repositories.get_mut(package.repository) {
+                    indices.retain(|&it| it != index);
+                    if indices.is_empty() {
+                        repositories.remove(package.repository);
+                    }
+                }
+                if repositories.is_empty() {
+                    self.names.remove(name);
+                }
+            }
+        }
+    }
+
+    /// Returns every version of the packages with that name or providing it,
+    /// by repository and latest version first.
+    pub fn providers(&self, name: &str) -> impl Iterator<Item = &Package> {
+        self.names
+            .get(name)
+            .into_iter()
+            .flat_map(|it| it.values())
+            .flatten()
+            .map(move |&index| &self.packages[index])
+    }
+
+    /// Returns the latest version of the package with that name, ignoring provided names.
+    pub fn named(&self, name: &str) -> Option<&Package> {
+        self.providers(name)
+            .filter(|&it| it.name() == name)
+            .max_by(|&a, &b| versions::compare(&a.version, &b.version))
+    }
+
+    /// Returns the latest version of the packages with that name or providing it.
+    pub fn latest_version(&self, name: &str) -> Option<&Package> {
+        self.names
+            .get(name)?
+            .values()
+            .map(|it| &self.packages[it[0]])
+            .max_by(|&a, &b| versions::compare(&a.version, &b.version))
+    }
+
+    /// Returns the names of the groups.
+    pub fn groups(&self) -> impl Iterator<Item = &str> {
+        self.groups.keys().map(|it| it.as_str())
+    }
+
+    /// Returns the latest version of the members of a group, by name.
+    pub fn group(&self, name: &str) -> Vec<&Package> {
+        self.groups
+            .get(name)
+            .into_iter()
+            .flatten()
+            .map(|&index| &self.packages[index])
+            .filter(|&it| self.named(it.name()) == Some(it))
+            .collect()
+    }
+
+    /// Returns the latest version of the packages of a repository with that name or providing it.
+    pub fn latest_in_repository(&self, name: &str, repository: &Repository) -> Option<&Package> {
+        self.names
+            .get(name)?
+            .get(repository)
+            .map(|it| &self.packages[it[0]])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn lookups() {
+        let mut catalogue = Catalogue::from(
+            [
+                "repository=msys\tname=bash\tprovides=sh\tversion=5.1.016-1\tgroups=base",
+                "repository=msys\tname=bash\tprovides=sh\tversion=5.1.008-1\tgroups=base",
+                "repository=msys\tname=dash\tprovides=sh\tversion=0.5-1\tgroups=base,shells",
+                "repository=mingw64\tname=mingw-w64-x86_64-zlib\tversion=1.2-1",
+            ]
+            .iter()
+            .map(|&it| Package::try_from(it).unwrap())
+            .collect::<BTreeSet<_>>(),
+        );
+        assert_eq!(catalogue.len(), 4);
+        assert_eq!(
+            catalogue.latest_version("bash").unwrap().version,
+            "5.1.016-1"
+        );
+        assert_eq!(catalogue.latest_version("sh").unwrap().name(), "bash");
+        assert!(catalogue.latest_version("zlib").is_none());
+        assert!(catalogue.named("sh").is_none());
+        assert_eq!(catalogue.named("dash").unwrap().version, "0.5-1");
+        assert!(catalogue
+            .latest_in_repository("mingw-w64-x86_64-zlib", &Repository::Msys)
+            .is_none());
+        let providers: Vec<_> = catalogue
+            .providers("sh")
+            .map(|it| format!("{} {}", it.name(), it.version))
+            .collect();
+        assert_eq!(
+            providers,
+            vec!["bash 5.1.016-1", "bash 5.1.008-1", "dash 0.5-1"]
+        );
+        assert_eq!(
+            catalogue.groups().collect::<Vec<_>>(),
+            vec!["base", "shells"]
+        );
+        let members: Vec<_> = catalogue
+            .group("base")
+            .iter()
+            .map(|it| format!("{} {}", it.name(), it.version))
+            .collect();
+        assert_eq!(members, vec!["bash 5.1.016-1", "dash 0.5-1"]);
+        assert!(catalogue.group("zsh").is_empty());
+        let package =
+            Package::try_from("repository=msys\tname=zsh\tprovides=sh\tversion=5.9-1").unwrap();
+        assert!(!catalogue.contains(&package));
+        catalogue.insert(package.clone());
+        assert!(catalogue.contains(&package));
+        assert_eq!(catalogue.providers("sh").count(), 4);
+        assert_eq!(catalogue.iter().last().unwrap().name(), "zsh");
+        // before the other packages, and replacing a package that isn't in the same groups
+        catalogue.insert(
+            Package::try_from("repository=msys\tname=ash\tprovides=sh\tversion=1.0-1").unwrap(),
+        );
+        catalogue.insert(
+            Package::try_from(
+                "repository=msys\tname=dash\tprovides=sh\tversion=0.5-1\tgroups=shells",
+            )
+            .unwrap(),
+        );
+        let rebuilt = Catalogue::from(catalogue.iter().cloned().collect::<BTreeSet<_>>());
+        assert_eq!(catalogue.len(), 6);
+        let providers = |catalogue: &Catalogue| {
+            catalogue
+                .providers("sh")
+                .map(|it| format!("{} {}", it.name(), it.version))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(providers(&catalogue), providers(&rebuilt));
+        assert_eq!(
+            providers(&catalogue),
+            vec![
+                "zsh 5.9-1",
+                "bash 5.1.016-1",
+                "bash 5.1.008-1",
+                "ash 1.0-1",
+                "dash 0.5-1"
+            ]
+        );
+        for group in ["base", "shells"] {
+            assert_eq!(catalogue.group(group), rebuilt.group(group));
+        }
+        assert_eq!(catalogue.group("base").len(), 1);
+        assert_eq!(
+            catalogue.named("dash").unwrap().metadata.groups,
+            vec!["shells"]
+        );
+        assert_eq!(catalogue.named("bash").unwrap().version, "5.1.016-1");
+    }
+}
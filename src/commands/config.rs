@@ -0,0 +1,124 @@
+use crate::commands::errors::Result;
+use crate::commands::format;
+use crate::commands::paths;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+/// The preferred provider for a dependency name, e.g. provider=sh,bash
+const PROVIDER_KEY: &str = "provider";
+/// The url of a mirror that keeps the previous versions of the packages of a repository,
+/// e.g. archive=mingw64,https://repo.msys2.org/mingw/mingw64/
+const ARCHIVE_KEY: &str = "archive";
+/// A package that is not updated, e.g. hold=mingw-w64-x86_64-gcc
+const HOLD_KEY: &str = "hold";
+
+/// Settings of a root directory, read from var/local/packages/config.
+/// Each line is a key=value setting. Unknown keys are ignored.
+#[derive(Debug, Default)]
+pub struct Config {
+    /// Package to use when several packages provide a dependency name.
+    pub providers: BTreeMap<String, String>,
+    /// Url of the archive mirror of each repository.
+    pub archives: BTreeMap<String, String>,
+    /// Packages that update skips.
+    pub holds: BTreeSet<String>,
+}
+
+/// Reads the configuration, or returns the default configuration if the file is missing.
+pub fn read(root_directory_path: &Path) -> Config {
+    let mut config = Config::default();
+    let content =
+        fs::read_to_string(paths::get_config_file_path(root_directory_path)).unwrap_or_default();
+    for line in content.lines() {
+        for (key, value) in format::fields(line) {
+            if key == HOLD_KEY {
+                config.holds.insert(format::unescape(value));
+                continue;
+            }
+            let map = match key {
+                PROVIDER_KEY => &mut config.providers,
+                ARCHIVE_KEY => &mut config.archives,
+                _ => continue,
+            };
+            if let [name, value] = format::split_list(value).as_slice() {
+                map.insert(name.clone(), value.clone());
+            }
+        }
+    }
+    config
+}
+
+/// Adds (hold=true) or removes the hold on a package, keeping the other settings.
+/// Returns false if the package was already in that state.
+pub fn set_hold(root_directory_path: &Path, name: &str, hold: bool) -> Result<bool> {
+    let path = paths::get_config_file_path(root_directory_path);
+    let content = fs::read_to_string(&path).unwrap_or_default();
+    let line = format::field(HOLD_KEY, name);
+    let mut lines: Vec<&str> = content.lines().filter(|&it| it != line).collect();
+    let held = lines.len() != content.lines().count();
+    if held == hold {
+        return Ok(false);
+    }
+    if hold {
+        lines.push(&line);
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, lines.join("\n"))?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    lazy_static! {
+        static ref DATA_DIR: PathBuf = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("data");
+    }
+
+    #[test]
+    fn providers() {
+        let root_directory_path = DATA_DIR.join("tmp_config");
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+        assert!(read(&root_directory_path).providers.is_empty());
+        let path = paths::get_config_file_path(&root_directory_path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(
+            &path,
+            "provider=sh,bash\nunknown=1\nprovider=invalid\narchive=msys,https://host/msys/\n",
+        )
+        .unwrap();
+        let config = read(&root_directory_path);
+        assert_eq!(config.providers.len(), 1);
+        assert_eq!(
+            config.providers.get("sh").map(|it| it.as_str()),
+            Some("bash")
+        );
+        assert_eq!(
+            config.archives.get("msys").map(|it| it.as_str()),
+            Some("https://host/msys/")
+        );
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+    }
+
+    #[test]
+    fn holds() {
+        let root_directory_path = DATA_DIR.join("tmp_config_holds");
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+        assert!(!set_hold(&root_directory_path, "gcc", false).unwrap());
+        assert!(set_hold(&root_directory_path, "gcc", true).unwrap());
+        assert!(!set_hold(&root_directory_path, "gcc", true).unwrap());
+        assert!(set_hold(&root_directory_path, "cmake", true).unwrap());
+        let path = paths::get_config_file_path(&root_directory_path);
+        let content = fs::read_to_string(&path).unwrap();
+        fs::write(&path, format!("provider=sh,bash\n{}", content)).unwrap();
+        assert!(set_hold(&root_directory_path, "gcc", false).unwrap());
+        let config = read(&root_directory_path);
+        assert_eq!(config.holds.iter().collect::<Vec<_>>(), vec!["cmake"]);
+        assert_eq!(config.providers.len(), 1);
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+    }
+}
@@ -1,118 +1,584 @@
-use crate::commands::available_packages;
+use crate::commands::catalogue::Catalogue;
+use crate::commands::config::Config;
 use crate::commands::packages::Package;
+use crate::commands::{utils, versions};
 use ansi_term::Color;
 use regex::Regex;
-use std::collections::{BTreeSet, VecDeque};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 
 // Returns the packages in installation order. Dependencies should be installed before dependents.
+// The packages and their missing dependencies form a graph. Cyclic dependencies
+// (e.g. libintl depends on gcc-libs and libiconv, and libiconv depends on gcc-libs and libintl)
+// are strongly connected components of that graph, and are installed together, by name.
+// The components are found with Tarjan's algorithm, which returns them in topological order
+// (a component is returned after all the components it depends on).
 pub fn list(
     packages: Vec<&Package>,
-    installed_packages: &BTreeSet<Package>,
-    available_packages: &BTreeSet<Package>,
+    installed_packages: &Catalogue,
+    available_packages: &Catalogue,
+    providers: &Providers,
 ) -> Vec<Package> {
-    let mut processed = Vec::new();
-    let mut packages: VecDeque<&Package> = VecDeque::from(packages);
-    // On each iteration of the loop, we take the first element.
-    // If it has missing dependencies, then we add those dependencies at the front of the list
-    // (removing eventual occurrences later in the list) and proceed to the next iteration.
-    // If it has no missing dependency, then we consume the package and remove it from the list.
-    // In order to prevent infinite loops in the case of cyclic dependencies
-    // (e.g. libintl depends on gcc-libs and libiconv, and libiconv depends on gcc-libs and libintl),
-    // we keep snapshots of the package list, and if we are resetting the package list to a previous
-    // state, then we need to consume the first package even if it has dependencies.
-    let mut snapshots = BTreeSet::new();
-    while !packages.is_empty() {
-        let &package = packages.front().unwrap();
-        let dependencies = package.dependencies.as_ref().and_then(|dependencies| {
-            let dependencies: Vec<_> = dependencies
-                .iter()
-                .filter_map(|dependency| {
-                    let dependency_package = dependency_name(dependency).and_then(|name| {
-                        available_packages::latest_version(name, &available_packages)
-                    });
-                    if dependency_package.is_none() {
-                        println!(
-                            "{}",
-                            Color::Red.paint(format!(
-                                "Could not find {} dependency: {}",
-                                package.name(),
-                                dependency
-                            ))
+    let graph = Graph::new(packages, installed_packages, available_packages, providers);
+    let mut tarjan = Tarjan {
+        graph: &graph,
+        index: vec![None; graph.nodes.len()],
+        low_link: vec![0; graph.nodes.len()],
+        on_stack: vec![false; graph.nodes.len()],
+        stack: Vec::new(),
+        next_index: 0,
+        components: Vec::new(),
+    };
+    for node in 0..graph.nodes.len() {
+        if tarjan.index[node].is_none() {
+            tarjan.visit(node);
+        }
+    }
+    tarjan
+        .components
+        .into_iter()
+        .flat_map(|mut component| {
+            component.sort_by_key(|&it| graph.nodes[it]);
+            component.into_iter().map(|it| graph.nodes[it].clone())
+        })
+        .collect()
+}
+
+/// The packages that are not installed yet, with edges from each package to its dependencies.
+struct Graph<'a> {
+    nodes: Vec<&'a Package>,
+    edges: Vec<Vec<usize>>,
+}
+
+impl<'a> Graph<'a> {
+    fn new(
+        packages: Vec<&'a Package>,
+        installed_packages: &'a Catalogue,
+        available_packages: &'a Catalogue,
+        providers: &Providers,
+    ) -> Self {
+        let mut graph = Graph {
+            nodes: Vec::new(),
+            edges: Vec::new(),
+        };
+        let mut indices: BTreeMap<&Package, usize> = BTreeMap::new();
+        for package in packages {
+            graph.add(package, &mut indices);
+        }
+        // the list of nodes grows as the dependencies are added
+        let mut node = 0;
+        while node < graph.nodes.len() {
+            let package = graph.nodes[node];
+            for dependency in package.dependencies.iter().flatten() {
+                let dependency_package = providers.resolve(
+                    dependency,
+                    installed_packages,
+                    |it| indices.contains_key(it),
+                    available_packages,
+                );
+                match dependency_package {
+                    None => println!(
+                        "{}",
+                        Color::Red.paint(format!(
+                            "Could not find {} dependency: {}",
+                            package.name(),
+                            dependency
+                        ))
+                    ),
+                    Some(dependency_package) => {
+                        if !installed_packages.contains(dependency_package) {
+                            let dependency_node = graph.add(dependency_package, &mut indices);
+                            if !graph.edges[node].contains(&dependency_node) {
+                                graph.edges[node].push(dependency_node);
+                            }
+                        }
+                    }
+                }
+            }
+            node += 1;
+        }
+        graph
+    }
+
+    fn add(&mut self, package: &'a Package, indices: &mut BTreeMap<&'a Package, usize>) -> usize {
+        *indices.entry(package).or_insert_with(|| {
+            self.nodes.push(package);
+            self.edges.push(Vec::new());
+            self.nodes.len() - 1
+        })
+    }
+}
+
+struct Tarjan<'a, 'b> {
+    graph: &'b Graph<'a>,
+    index: Vec<Option<usize>>,
+    low_link: Vec<usize>,
+    on_stack: Vec<bool>,
+    stack: Vec<usize>,
+    next_index: usize,
+    components: Vec<Vec<usize>>,
+}
+
+impl<'a, 'b> Tarjan<'a, 'b> {
+    /// Depth-first search with an explicit stack of (node, next edge), so that long chains of
+    /// dependencies can't overflow the call stack.
+    fn visit(&mut self, root: usize) {
+        let mut path = vec![(root, 0)];
+        self.enter(root);
+        while let Some(&mut (node, ref mut edge)) = path.last_mut() {
+            if let Some(&dependency) = self.graph.edges[node].get(*edge) {
+                *edge += 1;
+                match self.index[dependency] {
+                    None => {
+                        self.enter(dependency);
+                        path.push((dependency, 0));
+                    }
+                    Some(index) if self.on_stack[dependency] => {
+                        self.low_link[node] = self.low_link[node].min(index);
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            path.pop();
+            if let Some(&(parent, _)) = path.last() {
+                self.low_link[parent] = self.low_link[parent].min(self.low_link[node]);
+            }
+            if Some(self.low_link[node]) == self.index[node] {
+                let mut component = Vec::new();
+                while let Some(member) = self.stack.pop() {
+                    self.on_stack[member] = false;
+                    component.push(member);
+                    if member == node {
+                        break;
+                    }
+                }
+                self.components.push(component);
+            }
+        }
+    }
+
+    fn enter(&mut self, node: usize) {
+        self.index[node] = Some(self.next_index);
+        self.low_link[node] = self.next_index;
+        self.next_index += 1;
+        self.stack.push(node);
+        self.on_stack[node] = true;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum NodeState {
+    /// Not installed yet, the dependencies are listed as children.
+    New,
+    /// Already installed, the dependencies are not listed.
+    Installed,
+    /// Depends on one of its ancestors.
+    Cycle,
+    /// Already listed (with its dependencies) earlier in the tree.
+    Repeated,
+    /// No package with that name (or providing it) was found.
+    Missing,
+}
+
+/// Node of a dependency tree.
+#[derive(Debug)]
+pub struct TreeNode<'a> {
+    /// The package name, or the dependency as written by the dependent package if it is missing.
+    pub name: &'a str,
+    pub package: Option<&'a Package>,
+    pub state: NodeState,
+    pub children: Vec<TreeNode<'a>>,
+}
+
+/// Returns the dependency trees of the packages.
+/// Dependencies are resolved like list does, and each package is only expanded once.
+pub fn tree<'a>(
+    packages: Vec<&'a Package>,
+    installed_packages: &'a Catalogue,
+    available_packages: &'a Catalogue,
+    providers: &Providers,
+) -> Vec<TreeNode<'a>> {
+    let roots = packages.clone();
+    let mut expanded = BTreeSet::new();
+    packages
+        .into_iter()
+        .map(|package| {
+            expanded.insert(package);
+            let mut ancestors = vec![package];
+            TreeNode {
+                name: package.name(),
+                package: Some(package),
+                state: if installed_packages.contains(package) {
+                    NodeState::Installed
+                } else {
+                    NodeState::New
+                },
+                children: children(
+                    &mut ancestors,
+                    &mut expanded,
+                    &roots,
+                    installed_packages,
+                    available_packages,
+                    providers,
+                ),
+            }
+        })
+        .collect()
+}
+
+fn children<'a>(
+    ancestors: &mut Vec<&'a Package>,
+    expanded: &mut BTreeSet<&'a Package>,
+    roots: &[&'a Package],
+    installed_packages: &'a Catalogue,
+    available_packages: &'a Catalogue,
+    providers: &Providers,
+) -> Vec<TreeNode<'a>> {
+    let package = *ancestors.last().unwrap();
+    package
+        .dependencies
+        .iter()
+        .flatten()
+        .map(|dependency| {
+            let dependency_package = providers.resolve(
+                dependency,
+                installed_packages,
+                |it| roots.contains(&it),
+                available_packages,
+            );
+            match dependency_package {
+                None => TreeNode {
+                    name: dependency,
+                    package: None,
+                    state: NodeState::Missing,
+                    children: vec![],
+                },
+                Some(dependency_package) => {
+                    let state = if installed_packages.contains(dependency_package) {
+                        NodeState::Installed
+                    } else if ancestors.contains(&dependency_package) {
+                        NodeState::Cycle
+                    } else if !expanded.insert(dependency_package) {
+                        NodeState::Repeated
+                    } else {
+                        NodeState::New
+                    };
+                    let children = if state == NodeState::New {
+                        ancestors.push(dependency_package);
+                        let children = children(
+                            ancestors,
+                            expanded,
+                            roots,
+                            installed_packages,
+                            available_packages,
+                            providers,
                         );
+                        ancestors.pop();
+                        children
+                    } else {
+                        vec![]
+                    };
+                    TreeNode {
+                        name: dependency_package.name(),
+                        package: Some(dependency_package),
+                        state,
+                        children,
                     }
-                    dependency_package
-                })
-                .filter(|&package| {
-                    !installed_packages.contains(package) && !processed.contains(package)
-                })
-                .collect();
-            if dependencies.is_empty() {
-                None
-            } else {
-                Some(dependencies)
+                }
             }
-        });
-        let snapshot = packages
-            .iter()
-            .map(|&it| it.name())
-            .collect::<Vec<_>>()
-            .join(", ");
-        // println!("{}", snapshot);
-        if snapshots.insert(snapshot) && dependencies.is_some() {
-            let mut dependencies = dependencies.unwrap();
-            dependencies.sort_by_key(|&it| {
-                -1 * it
-                    .dependencies
-                    .as_ref()
-                    .map(|it| it.len() as i8)
-                    .unwrap_or(0)
-            });
-            for dependency in dependencies {
-                packages.retain(|&it| it != dependency);
-                packages.push_front(dependency);
+        })
+        .collect()
+}
+
+/// Returns a Graphviz graph of the packages and of their dependencies.
+/// Installed dependencies that are not part of the list are drawn with a dashed outline.
+pub fn dot(
+    packages: &[Package],
+    installed_packages: &Catalogue,
+    available_packages: &Catalogue,
+    providers: &Providers,
+) -> String {
+    let mut lines = vec!["digraph dependencies {".to_string()];
+    let mut installed_nodes = BTreeSet::new();
+    for package in packages {
+        lines.push(format!(
+            "  \"{}\" [label=\"{}\\n{}\"];",
+            package.name(),
+            package.name(),
+            package.version
+        ));
+    }
+    for package in packages {
+        for dependency in package.dependencies.iter().flatten() {
+            if let Some(dependency) = providers.resolve(
+                dependency,
+                installed_packages,
+                |it| packages.contains(it),
+                available_packages,
+            ) {
+                if !packages.contains(dependency) {
+                    if !installed_packages.contains(dependency) {
+                        continue;
+                    }
+                    installed_nodes.insert(dependency);
+                }
+                lines.push(format!(
+                    "  \"{}\" -> \"{}\";",
+                    package.name(),
+                    dependency.name()
+                ));
             }
-        } else {
-            let package = packages.pop_front().unwrap();
-            if !processed.contains(package) {
-                processed.push(package.clone());
+        }
+    }
+    for package in installed_nodes {
+        lines.push(format!(
+            "  \"{}\" [label=\"{}\\n{}\", style=dashed];",
+            package.name(),
+            package.name(),
+            package.version
+        ));
+    }
+    lines.push("}".to_string());
+    lines.join("\n")
+}
+
+/// Returns the optional dependencies of the packages that are not installed yet.
+pub fn optional<'a>(
+    packages: &[&'a Package],
+    installed_packages: &'a Catalogue,
+    available_packages: &'a Catalogue,
+    providers: &Providers,
+) -> Vec<&'a Package> {
+    let mut optional: Vec<&Package> = Vec::new();
+    for package in packages {
+        for (dependency, _) in package
+            .optional_dependencies
+            .iter()
+            .map(|it| optional_dependency(it))
+        {
+            let dependency_package = providers.resolve(
+                dependency,
+                installed_packages,
+                |it| packages.contains(&it) || optional.contains(&it),
+                available_packages,
+            );
+            if let Some(dependency_package) = dependency_package {
+                if !installed_packages.contains(dependency_package)
+                    && !packages.contains(&dependency_package)
+                    && !optional.contains(&dependency_package)
+                {
+                    optional.push(dependency_package);
+                }
             }
         }
     }
-    // println!(
-    //     "{}",
-    //     processed
-    //         .iter()
-    //         .map(|it| it.name.to_string())
-    //         .collect::<Vec<_>>()
-    //         .join(", ")
-    // );
-    processed
+    optional
+}
+
+/// Splits an optional dependency into the dependency and the reason, if there is one
+/// (e.g. python: for the plugin interface).
+pub fn optional_dependency(value: &str) -> (&str, Option<&str>) {
+    match value.split_once(": ") {
+        Some((dependency, reason)) => (dependency.trim(), Some(reason.trim())),
+        None => (value.trim(), None),
+    }
+}
+
+/// Returns the packages installed as dependencies that are not required any more,
+/// directly or indirectly, by a package installed explicitly.
+/// Installed optional dependencies of required packages are also required.
+pub fn orphans(installed_packages: &Catalogue) -> Vec<&Package> {
+    let mut required: BTreeSet<&Package> = BTreeSet::new();
+    let mut queue: VecDeque<&Package> = installed_packages
+        .iter()
+        .filter(|&it| it.explicit())
+        .collect();
+    while let Some(package) = queue.pop_front() {
+        if !required.insert(package) {
+            continue;
+        }
+        package
+            .dependencies
+            .iter()
+            .flatten()
+            .map(|it| it.as_str())
+            .chain(
+                package
+                    .optional_dependencies
+                    .iter()
+                    .map(|it| optional_dependency(it).0),
+            )
+            .filter_map(dependency_name)
+            .filter_map(|name| installed_packages.latest_version(name))
+            .for_each(|it| queue.push_back(it));
+    }
+    installed_packages
+        .iter()
+        .filter(|&it| !required.contains(it))
+        .collect()
 }
 
-fn dependency_name(name_with_optional_version: &str) -> Option<&str> {
+pub fn dependency_name(name_with_optional_version: &str) -> Option<&str> {
     lazy_static! {
-        static ref RE: Regex = Regex::new("[=>~#*]").unwrap();
+        static ref RE: Regex = Regex::new("[=<>~#*]").unwrap();
     };
-    (*RE)
-        .split(name_with_optional_version)
-        .into_iter()
-        .next()
-        .map(|it| match it {
-            "sh" => "bash",
-            it => it,
-        })
+    (*RE).split(name_with_optional_version).next()
+}
+
+/// Returns true if the version satisfies the version constraint of the dependency
+/// (e.g. gcc-libs>=12.1), or if the dependency doesn't have one.
+pub fn satisfies(dependency: &str, version: &str) -> bool {
+    let name = dependency_name(dependency).unwrap_or(dependency);
+    let constraint = &dependency[name.len()..];
+    let ordering = |required: &str| versions::compare(version, required);
+    if let Some(required) = constraint.strip_prefix(">=") {
+        ordering(required) != Ordering::Less
+    } else if let Some(required) = constraint.strip_prefix("<=") {
+        ordering(required) != Ordering::Greater
+    } else if let Some(required) = constraint.strip_prefix('=') {
+        ordering(required) == Ordering::Equal
+    } else if let Some(required) = constraint.strip_prefix('>') {
+        ordering(required) == Ordering::Greater
+    } else if let Some(required) = constraint.strip_prefix('<') {
+        ordering(required) == Ordering::Less
+    } else {
+        true
+    }
+}
+
+/// Chooses the package to use for a dependency name.
+/// A package that is already installed, or already selected, is used first.
+/// Then, a package with that name is preferred to packages that only provide it.
+/// If several packages provide it, the one configured for that name is used.
+/// Otherwise, the user is asked to choose, or the first one (by name) is used if prompts are
+/// disabled. The choice is remembered for the next dependencies with the same name.
+pub struct Providers {
+    preferences: BTreeMap<String, String>,
+    choices: RefCell<BTreeMap<String, String>>,
+    prompt: bool,
+}
+
+impl Providers {
+    pub fn new(config: &Config, no_prompt: bool) -> Self {
+        Self {
+            preferences: config.providers.clone(),
+            choices: RefCell::new(BTreeMap::new()),
+            prompt: !no_prompt,
+        }
+    }
+
+    /// Returns the package (installed, selected or available) that satisfies the dependency
+    /// (e.g. gcc-libs>=12.1). The version constraint only applies to packages with that name,
+    /// because the versions of provided names are unknown.
+    /// An installed package that doesn't satisfy the constraint is resolved to a newer version.
+    pub fn resolve<'a>(
+        &self,
+        dependency: &str,
+        installed_packages: &'a Catalogue,
+        selected: impl Fn(&Package) -> bool,
+        available_packages: &'a Catalogue,
+    ) -> Option<&'a Package> {
+        let name = dependency_name(dependency)?;
+        let meets =
+            |package: &Package| package.name() != name || satisfies(dependency, &package.version);
+        if let Some(package) = installed_packages
+            .latest_version(name)
+            .filter(|&it| meets(it))
+        {
+            return Some(package);
+        }
+        if let Some(package) = available_packages
+            .providers(name)
+            .find(|&it| selected(it) && meets(it))
+        {
+            return Some(package);
+        }
+        // latest version of each package that provides the name
+        let mut candidates: BTreeMap<&str, &Package> = BTreeMap::new();
+        for package in available_packages.providers(name).filter(|&it| meets(it)) {
+            let candidate = candidates.entry(package.name()).or_insert(package);
+            if versions::compare(&package.version, &candidate.version) == Ordering::Greater {
+                *candidate = package;
+            }
+        }
+        if let Some(&package) = candidates.get(name) {
+            return Some(package);
+        }
+        if candidates.len() < 2 {
+            return candidates.into_values().next();
+        }
+        let preference = self
+            .preferences
+            .get(name)
+            .or(self.choices.borrow().get(name))
+            .and_then(|it| candidates.get(it.as_str()).copied());
+        if preference.is_some() {
+            return preference;
+        }
+        let candidates: Vec<&Package> = candidates.into_values().collect();
+        let package = if self.prompt {
+            println!(
+                "There are {} packages that provide {}:",
+                candidates.len(),
+                Color::Purple.paint(name)
+            );
+            for (i, package) in candidates.iter().enumerate() {
+                println!(
+                    "  {}) {} {} ({})",
+                    i + 1,
+                    Color::Purple.paint(package.name()),
+                    package.version,
+                    package.repository.name()
+                );
+            }
+            loop {
+                let answer = utils::text_input("Enter a number:", Some("1"));
+                match answer.parse::<usize>() {
+                    Ok(i) if i >= 1 && i <= candidates.len() => break candidates[i - 1],
+                    _ => {}
+                }
+            }
+        } else {
+            candidates[0]
+        };
+        self.choices
+            .borrow_mut()
+            .insert(name.to_string(), package.name().to_string());
+        Some(package)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::commands::packages::Packages;
+    use std::convert::TryFrom;
     use std::path::PathBuf;
+    use std::time::Instant;
 
     lazy_static! {
         static ref DATA_DIR: PathBuf = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("data");
     }
 
+    fn packages(lines: &[&str]) -> Catalogue {
+        Catalogue::from(
+            lines
+                .iter()
+                .map(|&it| Package::try_from(it).unwrap())
+                .collect::<BTreeSet<_>>(),
+        )
+    }
+
+    fn providers() -> Providers {
+        Providers::new(&Config::default(), true)
+    }
+
+    fn names(packages: &[Package]) -> Vec<&str> {
+        packages.iter().map(|it| it.name()).collect()
+    }
+
     #[test]
     fn dependency_names() {
         let name = dependency_name("test");
@@ -124,20 +590,352 @@ mod tests {
         let name = dependency_name("name>=1.0");
         assert!(name.is_some());
         assert_eq!("name", name.unwrap());
+        assert_eq!(dependency_name("name<2"), Some("name"));
+    }
+
+    #[test]
+    fn version_constraints() {
+        assert!(satisfies("gcc-libs", "11.2.0-1"));
+        assert!(satisfies("gcc-libs=11.2.0", "11.2.0-1"));
+        assert!(!satisfies("gcc-libs=12.1.0", "11.2.0-1"));
+        assert!(satisfies("gcc-libs>=11.2.0", "11.2.0-1"));
+        assert!(!satisfies("gcc-libs>11.2.0-1", "11.2.0-1"));
+        assert!(satisfies("gcc-libs<12", "11.2.0-1"));
+        assert!(!satisfies("gcc-libs<=11", "11.2.0-1"));
+    }
+
+    #[test]
+    fn dependency_providers() {
+        let available_packages = packages(&[
+            "repository=msys\tname=bash\tprovides=sh\tversion=5.1-1",
+            "repository=msys\tname=dash\tprovides=sh\tversion=0.5-1",
+            "repository=msys\tname=zsh\tprovides=sh\tversion=5.9-1",
+            "repository=msys\tname=perl\tversion=5.36-1",
+            "repository=msys\tname=perl-git\tprovides=perl\tversion=5.37-1",
+            "repository=msys\tname=app\tversion=1\tdepends=sh,perl",
+        ]);
+        let find = |name: &str| available_packages.latest_version(name);
+        let app = find("app").unwrap();
+        let empty = Catalogue::from(BTreeSet::new());
+        // the first provider (by name) without prompts, and the package named perl
+        let list1 = list(vec![app], &empty, &available_packages, &providers());
+        assert_eq!(names(&list1), vec!["bash", "perl", "app"]);
+        // configured preference
+        let mut config = Config::default();
+        config.providers.insert("sh".to_string(), "zsh".to_string());
+        let providers = Providers::new(&config, true);
+        let list2 = list(vec![app], &empty, &available_packages, &providers);
+        assert_eq!(names(&list2), vec!["zsh", "perl", "app"]);
+        // already selected
+        let list3 = list(
+            vec![app, find("dash").unwrap()],
+            &empty,
+            &available_packages,
+            &providers,
+        );
+        assert_eq!(names(&list3), vec!["dash", "perl", "app"]);
+        // already installed
+        let mut installed_packages = BTreeSet::new();
+        installed_packages.insert(find("dash").unwrap().clone());
+        installed_packages.insert(find("perl-git").unwrap().clone());
+        let installed_packages = Catalogue::from(installed_packages);
+        let list4 = list(
+            vec![app],
+            &installed_packages,
+            &available_packages,
+            &providers,
+        );
+        assert_eq!(names(&list4), vec!["app"]);
+    }
+
+    #[test]
+    fn dependency_versions() {
+        let available_packages = packages(&[
+            "repository=msys\tname=gcc-libs\tversion=11.2.0-1",
+            "repository=msys\tname=gcc-libs\tversion=12.1.0-1",
+            "repository=msys\tname=gcc\tversion=12.1.0-1\tdepends=gcc-libs=12.1.0-1",
+        ]);
+        let installed_packages = packages(&["repository=msys\tname=gcc-libs\tversion=11.2.0-1"]);
+        let resolve = |dependency: &str| {
+            providers()
+                .resolve(
+                    dependency,
+                    &installed_packages,
+                    |_| false,
+                    &available_packages,
+                )
+                .map(|it| it.version.as_str())
+        };
+        assert_eq!(resolve("gcc-libs"), Some("11.2.0-1"));
+        assert_eq!(resolve("gcc-libs<12"), Some("11.2.0-1"));
+        assert_eq!(resolve("gcc-libs>=12"), Some("12.1.0-1"));
+        assert_eq!(resolve("gcc-libs>12.1.0-1"), None);
+        // the installed version is too old and is updated with its dependent
+        let gcc = available_packages.latest_version("gcc").unwrap();
+        let list = list(
+            vec![gcc],
+            &installed_packages,
+            &available_packages,
+            &providers(),
+        );
+        assert_eq!(
+            list.iter()
+                .map(|it| (it.name(), it.version.as_str()))
+                .collect::<Vec<_>>(),
+            vec![("gcc-libs", "12.1.0-1"), ("gcc", "12.1.0-1")]
+        );
+    }
+
+    #[test]
+    fn orphan_packages() {
+        let installed_packages = packages(&[
+            "repository=msys\tname=app\tversion=1\tdepends=lib1>=1.0\tinstalldate=1\treason=explicit",
+            "repository=msys\tname=lib1\tversion=1\tdepends=lib2\tinstalldate=1\treason=dependency",
+            "repository=msys\tname=lib2\tversion=1\tinstalldate=1\treason=dependency",
+            "repository=msys\tname=old\tversion=1\tdepends=lib3,lib2\tinstalldate=1\treason=dependency",
+            "repository=msys\tname=lib3\tversion=1\tinstalldate=1\treason=dependency",
+            "repository=msys\tname=legacy\tversion=1",
+        ]);
+        let orphans: Vec<_> = orphans(&installed_packages)
+            .iter()
+            .map(|it| it.name())
+            .collect();
+        assert_eq!(orphans, vec!["lib3", "old"]);
+        let installed_packages = packages(&[
+            "repository=msys\tname=app\tversion=1\toptdepends=python: for the plugin interface\tinstalldate=1\treason=explicit",
+            "repository=msys\tname=python\tversion=3\tdepends=libffi\tinstalldate=1\treason=dependency",
+            "repository=msys\tname=libffi\tversion=1\tinstalldate=1\treason=dependency",
+        ]);
+        assert!(super::orphans(&installed_packages).is_empty());
+    }
+
+    #[test]
+    fn optional_dependencies() {
+        assert_eq!(
+            optional_dependency("python: for the plugin interface"),
+            ("python", Some("for the plugin interface"))
+        );
+        assert_eq!(optional_dependency("perl"), ("perl", None));
+        let available_packages = packages(&[
+            "repository=msys\tname=app\tversion=1\toptdepends=python>=3: for plugins,perl,missing: never",
+            "repository=msys\tname=python\tversion=3\tdepends=libffi",
+            "repository=msys\tname=perl\tversion=5",
+            "repository=msys\tname=libffi\tversion=1",
+        ]);
+        let installed_packages = Catalogue::from(
+            available_packages
+                .iter()
+                .filter(|it| it.name() == "perl")
+                .cloned()
+                .collect::<BTreeSet<_>>(),
+        );
+        let app = available_packages.latest_version("app").unwrap();
+        let optional: Vec<_> = optional(
+            &[app],
+            &installed_packages,
+            &available_packages,
+            &providers(),
+        )
+        .iter()
+        .map(|it| it.name())
+        .collect();
+        assert_eq!(optional, vec!["python"]);
+    }
+
+    #[test]
+    fn dependency_list_with_cycle() {
+        // msys packages as found in the repository
+        let available_packages = packages(&[
+            "repository=msys\tname=gcc-libs\tversion=11.3.0-3",
+            "repository=msys\tname=libiconv\tversion=1.17-1\tdepends=gcc-libs,libintl",
+            "repository=msys\tname=libintl\tversion=0.21-2\tdepends=gcc-libs,libiconv",
+            "repository=msys\tname=gettext\tversion=0.21-2\tdepends=libintl,libgettextpo,libasprintf",
+            "repository=msys\tname=libgettextpo\tversion=0.21-2\tdepends=gcc-libs",
+            "repository=msys\tname=libasprintf\tversion=0.21-2\tdepends=gcc-libs",
+        ]);
+        let find = |name: &str| available_packages.latest_version(name);
+        let empty = Catalogue::from(BTreeSet::new());
+        let list1 = list(
+            vec![find("libintl").unwrap()],
+            &empty,
+            &available_packages,
+            &providers(),
+        );
+        assert_eq!(names(&list1), vec!["gcc-libs", "libiconv", "libintl"]);
+        let list2 = list(
+            vec![find("libiconv").unwrap()],
+            &empty,
+            &available_packages,
+            &providers(),
+        );
+        assert_eq!(names(&list2), names(&list1));
+        let list = list(
+            vec![find("gettext").unwrap()],
+            &empty,
+            &available_packages,
+            &providers(),
+        );
+        assert_eq!(
+            names(&list),
+            vec![
+                "gcc-libs",
+                "libiconv",
+                "libintl",
+                "libgettextpo",
+                "libasprintf",
+                "gettext"
+            ]
+        );
+        let mut installed_packages = BTreeSet::new();
+        installed_packages.insert(find("gcc-libs").unwrap().clone());
+        installed_packages.insert(find("libiconv").unwrap().clone());
+        let installed_packages = Catalogue::from(installed_packages);
+        let list = super::list(
+            vec![find("gettext").unwrap()],
+            &installed_packages,
+            &available_packages,
+            &providers(),
+        );
+        assert_eq!(
+            names(&list),
+            vec!["libintl", "libgettextpo", "libasprintf", "gettext"]
+        );
+    }
+
+    #[test]
+    fn dependency_list_with_many_dependencies() {
+        let mut lines: Vec<String> = (0..200)
+            .map(|i| format!("repository=msys\tname=lib{}\tversion=1", i))
+            .collect();
+        lines.push(format!(
+            "repository=msys\tname=app\tversion=1\tdepends={}",
+            (0..200)
+                .map(|i| format!("lib{}", i))
+                .collect::<Vec<_>>()
+                .join(",")
+        ));
+        let available_packages = packages(&lines.iter().map(|it| it.as_str()).collect::<Vec<_>>());
+        let app = available_packages.latest_version("app").unwrap();
+        let list = list(
+            vec![app],
+            &Catalogue::from(BTreeSet::new()),
+            &available_packages,
+            &providers(),
+        );
+        assert_eq!(list.len(), 201);
+        assert_eq!(list.first().unwrap().name(), "lib0");
+        assert_eq!(list.last().unwrap().name(), "app");
+    }
+
+    #[test]
+    fn dependency_list_with_large_catalogue() {
+        // every package depends on the next two, one of them through a provided name
+        let count = 20_000;
+        let lines: Vec<String> = (0..count)
+            .map(|i| {
+                format!(
+                    "repository=msys\tname=lib{}\tprovides=virtual{}\tversion=1.{}\t\
+                    depends=lib{},virtual{}",
+                    i,
+                    i,
+                    i % 7,
+                    (i + 1) % count,
+                    (i + 2) % count
+                )
+            })
+            .collect();
+        let available_packages = packages(&lines.iter().map(|it| it.as_str()).collect::<Vec<_>>());
+        let start = Instant::now();
+        let list = list(
+            available_packages.iter().collect(),
+            &Catalogue::from(BTreeSet::new()),
+            &available_packages,
+            &providers(),
+        );
+        assert_eq!(list.len(), count);
+        assert!(start.elapsed().as_secs() < 10);
+    }
+
+    #[test]
+    fn dependency_tree() {
+        let available_packages = packages(&[
+            "repository=msys\tname=app\tversion=1\tdepends=lib1,lib2,missing>=2",
+            "repository=msys\tname=lib1\tversion=1\tdepends=lib3",
+            "repository=msys\tname=lib2\tversion=1\tdepends=lib3,lib4",
+            "repository=msys\tname=lib3\tversion=1\tdepends=lib1",
+            "repository=msys\tname=lib4\tversion=1",
+        ]);
+        let installed_packages = Catalogue::from(
+            available_packages
+                .iter()
+                .filter(|it| it.name() == "lib4")
+                .cloned()
+                .collect::<BTreeSet<_>>(),
+        );
+        let app = available_packages.latest_version("app").unwrap();
+        let tree = tree(
+            vec![app],
+            &installed_packages,
+            &available_packages,
+            &providers(),
+        );
+        fn flatten(nodes: &[TreeNode], depth: usize, lines: &mut Vec<String>) {
+            for node in nodes {
+                lines.push(format!(
+                    "{}{} {:?}",
+                    " ".repeat(depth),
+                    node.name,
+                    node.state
+                ));
+                flatten(&node.children, depth + 1, lines);
+            }
+        }
+        let mut lines = vec![];
+        flatten(&tree, 0, &mut lines);
+        assert_eq!(
+            lines,
+            vec![
+                "app New",
+                " lib1 New",
+                "  lib3 New",
+                "   lib1 Cycle",
+                " lib2 New",
+                "  lib3 Repeated",
+                "  lib4 Installed",
+                " missing>=2 Missing",
+            ]
+        );
+        let packages = list(
+            vec![app],
+            &installed_packages,
+            &available_packages,
+            &providers(),
+        );
+        let graph = dot(
+            &packages,
+            &installed_packages,
+            &available_packages,
+            &providers(),
+        );
+        assert!(graph.starts_with("digraph dependencies {"));
+        assert!(graph.contains("  \"app\" -> \"lib1\";"));
+        assert!(graph.contains("  \"lib3\" -> \"lib1\";"));
+        assert!(graph.contains("  \"lib4\" [label=\"lib4\\n1\", style=dashed];"));
+        assert!(graph.ends_with('}'));
     }
 
     #[test]
     fn dependency_list_with_no_previous_installs() {
         let path = DATA_DIR.join("available_packages_file4.zst");
-        let available_packages = Packages::get_packages_from_file(&path).unwrap();
-        let package1 = available_packages::latest_version("package1", &available_packages);
+        let available_packages = Catalogue::from(Packages::get_packages_from_file(&path).unwrap());
+        let package1 = available_packages.latest_version("package1");
         assert!(package1.is_some());
         let package1 = package1.unwrap();
-        let package2 = available_packages::latest_version("package2", &available_packages);
+        let package2 = available_packages.latest_version("package2");
         assert!(package2.is_some());
         let package2 = package2.unwrap();
-        let empty = BTreeSet::new();
-        let list = super::list(vec![package1], &empty, &available_packages);
+        let empty = Catalogue::from(BTreeSet::new());
+        let list = super::list(vec![package1], &empty, &available_packages, &providers());
         assert_eq!(2, list.len());
         let first = list.first().unwrap();
         let last = list.last().unwrap();
@@ -148,16 +946,22 @@ mod tests {
     #[test]
     fn dependency_list_with_previous_installs() {
         let path = DATA_DIR.join("available_packages_file4.zst");
-        let available_packages = Packages::get_packages_from_file(&path).unwrap();
-        let package1 = available_packages::latest_version("package1", &available_packages);
+        let available_packages = Catalogue::from(Packages::get_packages_from_file(&path).unwrap());
+        let package1 = available_packages.latest_version("package1");
         assert!(package1.is_some());
         let package1 = package1.unwrap();
-        let package2 = available_packages::latest_version("package2", &available_packages);
+        let package2 = available_packages.latest_version("package2");
         assert!(package2.is_some());
         let package2 = package2.unwrap();
         let mut installed_packages = BTreeSet::new();
         installed_packages.insert(package2.clone());
-        let list = super::list(vec![package1], &installed_packages, &available_packages);
+        let installed_packages = Catalogue::from(installed_packages);
+        let list = super::list(
+            vec![package1],
+            &installed_packages,
+            &available_packages,
+            &providers(),
+        );
         assert_eq!(1, list.len());
         let first = list.first().unwrap();
         assert_eq!(package1, first);
@@ -8,6 +8,13 @@ pub enum Error {
     DownloadError,
     DecompressionError,
     ParseError,
+    Interrupted,
+    /// The root directory is locked by another process (PID, if the lock file could be read).
+    Locked(Option<u32>),
+    /// A PreTransaction hook with AbortOnFail failed (hook file name).
+    HookFailed(String),
+    /// A function of an install script failed (function name).
+    ScriptFailed(String),
 }
 
 pub type Result<T> = StdResult<T, Error>;
@@ -0,0 +1,223 @@
+use crate::commands::repositories::{Repository, RepositoryVersion};
+use crate::commands::utils::ETag;
+
+/// Version of the format used for the available and installed packages files.
+/// 1: header with space separated repository names and etags, positional tab separated columns.
+/// 2: header starting with #pmw, tab separated key=value fields.
+/// 3: optional dependencies (optdepends field).
+pub const FORMAT_VERSION: u32 = 3;
+
+const HEADER_PREFIX: &str = "#pmw";
+const FORMAT_KEY: &str = "format";
+const REPOSITORY_KEY: &str = "repository";
+
+/// First line of the available and installed packages files.
+#[derive(Debug)]
+pub struct Header {
+    pub version: u32,
+    pub repositories: Vec<RepositoryVersion>,
+}
+
+impl Header {
+    pub fn create(repositories: Vec<RepositoryVersion>) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            repositories,
+        }
+    }
+    /// Version 2: #pmw format=2 repository={repo_name},{etag} ...
+    /// Version 1: {repo_name} {etag} {repo_name} {etag} ...
+    pub fn parse(line: &str) -> Self {
+        if line.split('\t').next() == Some(HEADER_PREFIX) {
+            let fields = fields(line);
+            Self {
+                version: fields
+                    .iter()
+                    .find(|(key, _)| *key == FORMAT_KEY)
+                    .and_then(|(_, value)| unescape(value).parse().ok())
+                    .unwrap_or(FORMAT_VERSION),
+                repositories: fields
+                    .iter()
+                    .filter(|(key, _)| *key == REPOSITORY_KEY)
+                    .filter_map(|(_, value)| {
+                        let values = split_list(value);
+                        let repository = Repository::from(values.first()?)?;
+                        let etag = values.get(1)?;
+                        Some(RepositoryVersion {
+                            repository,
+                            etag: ETag::from(etag.as_str()),
+                        })
+                    })
+                    .collect(),
+            }
+        } else {
+            let cols: Vec<&str> = line.split(' ').collect();
+            Self {
+                version: 1,
+                repositories: cols
+                    .chunks(2)
+                    .filter_map(|it| {
+                        let repository = Repository::from(it[0])?;
+                        let etag = it.get(1)?;
+                        Some(RepositoryVersion {
+                            repository,
+                            etag: ETag::from(*etag),
+                        })
+                    })
+                    .collect(),
+            }
+        }
+    }
+}
+
+impl From<&Header> for String {
+    fn from(header: &Header) -> Self {
+        let mut cols = vec![
+            HEADER_PREFIX.to_string(),
+            field(FORMAT_KEY, &header.version.to_string()),
+        ];
+        header.repositories.iter().for_each(|it| {
+            cols.push(list_field(
+                REPOSITORY_KEY,
+                &[it.repository.name().to_string(), it.etag.value.clone()],
+            ))
+        });
+        cols.join("\t")
+    }
+}
+
+/// Splits a line into (key, escaped value) pairs. Columns without '=' are skipped.
+pub fn fields(line: &str) -> Vec<(&str, &str)> {
+    line.split('\t')
+        .filter_map(|col| {
+            col.find('=')
+                .map(|position| (&col[..position], &col[position + 1..]))
+        })
+        .collect()
+}
+
+pub fn field(key: &str, value: &str) -> String {
+    format!("{}={}", key, escape(value))
+}
+
+pub fn list_field(key: &str, values: &[String]) -> String {
+    format!(
+        "{}={}",
+        key,
+        values
+            .iter()
+            .map(|it| escape(it))
+            .collect::<Vec<_>>()
+            .join(",")
+    )
+}
+
+/// Escapes backslashes, tabs, new lines and commas.
+pub fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            ',' => escaped.push_str("\\,"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('t') => unescaped.push('\t'),
+                Some('n') => unescaped.push('\n'),
+                Some('r') => unescaped.push('\r'),
+                Some(c) => unescaped.push(c),
+                None => unescaped.push('\\'),
+            }
+        } else {
+            unescaped.push(c);
+        }
+    }
+    unescaped
+}
+
+/// Splits an escaped value on unescaped commas and unescapes each item.
+/// An empty value is an empty list.
+pub fn split_list(value: &str) -> Vec<String> {
+    if value.is_empty() {
+        return vec![];
+    }
+    let mut items = Vec::new();
+    let mut current = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                current.push(c);
+                if let Some(c) = chars.next() {
+                    current.push(c);
+                }
+            }
+            ',' => items.push(unescape(&std::mem::take(&mut current))),
+            c => current.push(c),
+        }
+    }
+    items.push(unescape(&current));
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaping() {
+        let value = "a\tb\nc\\d,e\rf";
+        assert_eq!(escape(value), "a\\tb\\nc\\\\d\\,e\\rf");
+        assert_eq!(unescape(&escape(value)), value);
+        assert!(!escape(value).contains('\t'));
+    }
+
+    #[test]
+    fn lists() {
+        let values = vec!["a,b".to_string(), "".to_string(), "c\\".to_string()];
+        let field = list_field("key", &values);
+        assert_eq!(field, "key=a\\,b,,c\\\\");
+        let fields = fields(&field);
+        assert_eq!(fields, vec![("key", "a\\,b,,c\\\\")]);
+        assert_eq!(split_list(fields[0].1), values);
+        assert!(split_list("").is_empty());
+    }
+
+    #[test]
+    fn headers() {
+        let header = Header::parse("msys 1 mingw64 2");
+        assert_eq!(header.version, 1);
+        assert_eq!(header.repositories.len(), 2);
+        assert_eq!(header.repositories[0].repository, &Repository::Msys);
+        assert_eq!(header.repositories[1].etag.value, "2");
+        let header = Header::parse("");
+        assert_eq!(header.version, 1);
+        assert!(header.repositories.is_empty());
+        let header = Header::create(vec![RepositoryVersion {
+            repository: &Repository::Mingw64,
+            etag: ETag::from("\"61a,f\""),
+        }]);
+        let line = String::from(&header);
+        assert_eq!(line, "#pmw\tformat=3\trepository=mingw64,\"61a\\,f\"");
+        let header = Header::parse(&line);
+        assert_eq!(header.version, FORMAT_VERSION);
+        assert_eq!(header.repositories.len(), 1);
+        assert_eq!(header.repositories[0].repository, &Repository::Mingw64);
+        assert_eq!(header.repositories[0].etag.value, "\"61a,f\"");
+        let header = Header::parse("#pmw\tformat=4\tunknown=value");
+        assert_eq!(header.version, 4);
+        assert!(header.repositories.is_empty());
+    }
+}
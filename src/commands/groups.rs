@@ -0,0 +1,136 @@
+use crate::commands::catalogue::Catalogue;
+use crate::commands::packages::Package;
+use crate::commands::repositories::Repository;
+use crate::commands::utils;
+use ansi_term::Color;
+use std::collections::BTreeSet;
+
+/// Finds a group from a name typed by the user, the same way as packages:
+/// the name can be qualified with a repository (e.g. mingw64/toolchain), and the repository
+/// package prefix can be omitted.
+pub fn find(
+    name: &str,
+    environment: Option<&'static Repository>,
+    packages: &Catalogue,
+) -> Option<String> {
+    let names = match (name.split_once('/'), environment) {
+        (Some((repository, name)), _) => vec![Repository::from(repository)?.qualified_name(name)],
+        (None, Some(environment)) => vec![environment.qualified_name(name), name.to_string()],
+        (None, None) => vec![
+            name.to_string(),
+            Repository::default_environment().qualified_name(name),
+        ],
+    };
+    names.into_iter().find(|it| !packages.group(it).is_empty())
+}
+
+/// Asks which members of the group should be installed (all of them by default).
+pub fn select<'a>(group: &str, members: &[&'a Package], no_prompt: bool) -> Vec<&'a Package> {
+    if no_prompt {
+        return members.to_vec();
+    }
+    println!(
+        "There are {} members in group {}:",
+        members.len(),
+        Color::Purple.paint(group)
+    );
+    for (index, member) in members.iter().enumerate() {
+        println!("{:>4}) {} {}", index + 1, member.name(), &member.version);
+    }
+    loop {
+        let input = utils::text_input("Enter a selection:", Some("all"));
+        match selection(&input, members.len()) {
+            Some(indices) => return indices.into_iter().map(|it| members[it]).collect(),
+            None => println!("{}", Color::Red.paint("Invalid selection.")),
+        }
+    }
+}
+
+/// Parses a selection of members (numbered from 1 to count) and returns their indices.
+/// The selection is a list of numbers and ranges (1 3-5), separated by spaces or commas.
+/// Numbers and ranges starting with ^ are excluded instead, from every member if nothing else
+/// is selected (^2 selects every member but the second one).
+pub fn selection(input: &str, count: usize) -> Option<BTreeSet<usize>> {
+    let input = input.trim();
+    if input == "all" {
+        return Some((0..count).collect());
+    }
+    let mut included = BTreeSet::new();
+    let mut excluded = BTreeSet::new();
+    for item in input.split(|c: char| c == ',' || c.is_whitespace()) {
+        if item.is_empty() {
+            continue;
+        }
+        let (set, item) = match item.strip_prefix('^') {
+            Some(item) => (&mut excluded, item),
+            None => (&mut included, item),
+        };
+        let (first, last) = item.split_once('-').unwrap_or((item, item));
+        let (first, last): (usize, usize) = (first.parse().ok()?, last.parse().ok()?);
+        if first == 0 || first > last || last > count {
+            return None;
+        }
+        set.extend(first - 1..last);
+    }
+    if included.is_empty() {
+        included = (0..count).collect();
+    }
+    Some(included.difference(&excluded).copied().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn selections() {
+        let selection = |input: &str| selection(input, 5).map(|it| it.into_iter().collect());
+        assert_eq!(selection("all"), Some(vec![0, 1, 2, 3, 4]));
+        assert_eq!(selection(""), Some(vec![0, 1, 2, 3, 4]));
+        assert_eq!(selection("1 3-4"), Some(vec![0, 2, 3]));
+        assert_eq!(selection("5,1"), Some(vec![0, 4]));
+        assert_eq!(selection("^2"), Some(vec![0, 2, 3, 4]));
+        assert_eq!(selection("^2-4"), Some(vec![0, 4]));
+        assert_eq!(selection("1-3 ^2"), Some(vec![0, 2]));
+        assert_eq!(selection("0"), None);
+        assert_eq!(selection("6"), None);
+        assert_eq!(selection("3-2"), None);
+        assert_eq!(selection("gcc"), None);
+    }
+
+    #[test]
+    fn groups() {
+        let packages = Catalogue::from(
+            [
+                "repository=msys\tname=make\tversion=4.3-1\tgroups=base-devel",
+                "repository=mingw64\tname=mingw-w64-x86_64-gcc\tversion=11.2-1\tgroups=mingw-w64-x86_64-toolchain",
+                "repository=ucrt64\tname=mingw-w64-ucrt-x86_64-gcc\tversion=11.2-1\tgroups=mingw-w64-ucrt-x86_64-toolchain",
+            ]
+            .iter()
+            .map(|&it| Package::try_from(it).unwrap())
+            .collect::<BTreeSet<_>>(),
+        );
+        let find = |name: &str, environment: Option<&'static Repository>| {
+            super::find(name, environment, &packages)
+        };
+        assert_eq!(find("base-devel", None).unwrap(), "base-devel");
+        assert_eq!(
+            find("toolchain", None).unwrap(),
+            "mingw-w64-x86_64-toolchain"
+        );
+        assert_eq!(
+            find("toolchain", Some(&Repository::Ucrt64)).unwrap(),
+            "mingw-w64-ucrt-x86_64-toolchain"
+        );
+        assert_eq!(
+            find("ucrt64/toolchain", None).unwrap(),
+            "mingw-w64-ucrt-x86_64-toolchain"
+        );
+        assert!(find("make", None).is_none());
+        assert_eq!(
+            select("base-devel", &packages.group("base-devel"), true).len(),
+            1
+        );
+    }
+}
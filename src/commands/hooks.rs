@@ -0,0 +1,450 @@
+use crate::commands::errors::{Error, Result};
+use crate::commands::scripts::Runner;
+use crate::commands::search;
+use ansi_term::Color;
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Hooks shipped by the packages.
+const HOOKS_DIRECTORY: &str = "usr/share/libalpm/hooks";
+/// Hooks added by the user, they replace the package hooks with the same file name.
+const USER_HOOKS_DIRECTORY: &str = "etc/pacman.d/hooks";
+const HOOK_EXTENSION: &str = "hook";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Operation {
+    Install,
+    Upgrade,
+    Remove,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum When {
+    PreTransaction,
+    PostTransaction,
+}
+
+/// Path triggers match the files of the packages (File is the deprecated name of Path),
+/// package triggers match the package names.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum TriggerType {
+    Path,
+    Package,
+}
+
+#[derive(Debug)]
+struct Trigger {
+    operations: Vec<Operation>,
+    trigger_type: TriggerType,
+    /// Glob patterns, the ones starting with ! exclude what they match.
+    targets: Vec<String>,
+}
+
+/// A libalpm hook file: one or more [Trigger] sections and one [Action] section.
+/// See alpm-hooks(5).
+#[derive(Debug)]
+pub struct Hook {
+    name: String,
+    triggers: Vec<Trigger>,
+    description: Option<String>,
+    when: When,
+    exec: String,
+    abort_on_fail: bool,
+    needs_targets: bool,
+}
+
+/// The paths and the packages changed by a transaction, for matching the hook triggers.
+#[derive(Debug, Default)]
+pub struct Changes {
+    paths: BTreeSet<(Operation, String)>,
+    packages: BTreeSet<(Operation, String)>,
+}
+
+impl Changes {
+    /// Adds a package and its files (relative paths, directories end with a slash).
+    pub fn add(&mut self, operation: Operation, name: &str, files: &[String]) {
+        self.packages.insert((operation, name.to_string()));
+        for file in files {
+            self.paths.insert((operation, file.clone()));
+        }
+    }
+}
+
+impl Hook {
+    /// Parses the content of a hook file. Unknown keys are ignored.
+    pub fn parse(name: &str, content: &str) -> Result<Self> {
+        let mut triggers = Vec::new();
+        let mut description = None;
+        let mut when = None;
+        let mut exec = None;
+        let mut abort_on_fail = false;
+        let mut needs_targets = false;
+        let mut section = "";
+        for line in content.lines().map(|it| it.trim()) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                section = &line[1..line.len() - 1];
+                if section == "Trigger" {
+                    triggers.push(Trigger {
+                        operations: Vec::new(),
+                        trigger_type: TriggerType::Path,
+                        targets: Vec::new(),
+                    });
+                }
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => (line, ""),
+            };
+            match (section, key) {
+                ("Trigger", "Operation") => {
+                    let operation = match value {
+                        "Install" => Operation::Install,
+                        "Upgrade" => Operation::Upgrade,
+                        "Remove" => Operation::Remove,
+                        _ => return Err(Error::ParseError),
+                    };
+                    triggers.last_mut().unwrap().operations.push(operation);
+                }
+                ("Trigger", "Type") => {
+                    triggers.last_mut().unwrap().trigger_type = match value {
+                        "Path" | "File" => TriggerType::Path,
+                        "Package" => TriggerType::Package,
+                        _ => return Err(Error::ParseError),
+                    };
+                }
+                ("Trigger", "Target") => {
+                    triggers.last_mut().unwrap().targets.push(value.to_string())
+                }
+                ("Action", "Description") => description = Some(value.to_string()),
+                ("Action", "When") => {
+                    when = match value {
+                        "PreTransaction" => Some(When::PreTransaction),
+                        "PostTransaction" => Some(When::PostTransaction),
+                        _ => return Err(Error::ParseError),
+                    };
+                }
+                ("Action", "Exec") => exec = Some(value.to_string()),
+                ("Action", "AbortOnFail") => abort_on_fail = true,
+                ("Action", "NeedsTargets") => needs_targets = true,
+                ("Trigger", _) | ("Action", _) => {}
+                _ => return Err(Error::ParseError),
+            }
+        }
+        if triggers.is_empty()
+            || triggers
+                .iter()
+                .any(|it| it.operations.is_empty() || it.targets.is_empty())
+        {
+            return Err(Error::ParseError);
+        }
+        Ok(Self {
+            name: name.to_string(),
+            triggers,
+            description,
+            when: when.ok_or(Error::ParseError)?,
+            exec: exec.ok_or(Error::ParseError)?,
+            // AbortOnFail only applies to PreTransaction hooks
+            abort_on_fail: abort_on_fail && when == Some(When::PreTransaction),
+            needs_targets,
+        })
+    }
+
+    /// Returns the paths or the package names that trigger the hook, sorted.
+    fn targets(&self, changes: &Changes) -> Vec<String> {
+        let mut targets = BTreeSet::new();
+        for trigger in &self.triggers {
+            let patterns: Vec<(bool, Regex)> = trigger
+                .targets
+                .iter()
+                .filter_map(|it| {
+                    let (excluded, glob) = match it.strip_prefix('!') {
+                        Some(glob) => (true, glob),
+                        None => (false, it.as_str()),
+                    };
+                    let regex = Regex::new(&format!("^{}$", search::glob_to_regex(glob))).ok()?;
+                    Some((excluded, regex))
+                })
+                .collect();
+            let changed = match trigger.trigger_type {
+                TriggerType::Path => &changes.paths,
+                TriggerType::Package => &changes.packages,
+            };
+            for (operation, value) in changed {
+                // the last pattern that matches decides
+                if trigger.operations.contains(operation)
+                    && patterns
+                        .iter()
+                        .rev()
+                        .find(|(_, regex)| regex.is_match(value))
+                        .map(|(excluded, _)| !excluded)
+                        .unwrap_or(false)
+                {
+                    targets.insert(value.clone());
+                }
+            }
+        }
+        targets.into_iter().collect()
+    }
+
+    /// Runs the Exec command, with the targets on stdin if NeedsTargets
+    /// is set. Returns true if the command succeeded.
+    fn execute(&self, runner: &Runner, targets: &[String]) -> bool {
+        let input = format!("{}\n", targets.join("\n"));
+        let input = if self.needs_targets {
+            Some(input.as_str())
+        } else {
+            None
+        };
+        match runner.run(&self.exec, &[], input) {
+            Ok(output) => {
+                output.print(&format!("Hook {}", self.name));
+                output.success()
+            }
+            Err(err) => {
+                println!(
+                    "{}",
+                    Color::Red.paint(format!("Failed to run hook {}", self.name))
+                );
+                println!("{:?}", err);
+                false
+            }
+        }
+    }
+}
+
+/// Reads the hook files of the root directory, sorted by file name.
+/// Files that can't be parsed are skipped with a warning.
+pub fn load(root_directory_path: &Path) -> Vec<Hook> {
+    let mut paths: BTreeMap<String, PathBuf> = BTreeMap::new();
+    for directory in [HOOKS_DIRECTORY, USER_HOOKS_DIRECTORY] {
+        if let Ok(entries) = fs::read_dir(root_directory_path.join(directory)) {
+            for entry in entries.filter_map(|it| it.ok()) {
+                let path = entry.path();
+                if path.extension().map(|it| it == HOOK_EXTENSION) == Some(true) {
+                    paths.insert(entry.file_name().to_string_lossy().to_string(), path);
+                }
+            }
+        }
+    }
+    paths
+        .into_iter()
+        .filter_map(|(name, path)| {
+            let content = fs::read_to_string(&path).ok()?;
+            // an empty user hook disables the package hook with the same name
+            if content.trim().is_empty() {
+                return None;
+            }
+            match Hook::parse(&name, &content) {
+                Ok(hook) => Some(hook),
+                Err(_) => {
+                    println!("{}", Color::Red.paint(format!("Invalid hook {}", name)));
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Runs the hooks of the root directory that are triggered by the changes, once each.
+/// Returns an error if a PreTransaction hook with AbortOnFail fails.
+pub fn run(
+    root_directory_path: &Path,
+    runner: &Runner,
+    when: When,
+    changes: &Changes,
+) -> Result<()> {
+    let hooks: Vec<(Hook, Vec<String>)> = load(root_directory_path)
+        .into_iter()
+        .filter(|it| it.when == when)
+        .map(|it| {
+            let targets = it.targets(changes);
+            (it, targets)
+        })
+        .filter(|(_, targets)| !targets.is_empty())
+        .collect();
+    let count = hooks.len();
+    for (index, (hook, targets)) in hooks.into_iter().enumerate() {
+        println!(
+            "({}/{}) {}",
+            index + 1,
+            count,
+            hook.description.as_deref().unwrap_or(&hook.name)
+        );
+        if !hook.execute(runner, &targets) && hook.abort_on_fail {
+            return Err(Error::HookFailed(hook.name));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::scripts;
+
+    lazy_static! {
+        static ref DATA_DIR: PathBuf = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("data");
+    }
+
+    const INFO_HOOK: &str = r#"
+[Trigger]
+Type = Path
+Operation = Install
+Operation = Upgrade
+Target = usr/share/info/*
+Target = !usr/share/info/dir
+
+[Action]
+Description = Updating the info directory file...
+When = PostTransaction
+Exec = cat >> info.log
+NeedsTargets
+"#;
+
+    const CHECK_HOOK: &str = r#"
+[Trigger]
+Type = Package
+Operation = Remove
+Target = gcc
+
+[Action]
+When = PreTransaction
+Exec = echo pre >> check.log; exit 1
+AbortOnFail
+"#;
+
+    fn write_hook(root_directory_path: &Path, directory: &str, name: &str, content: &str) {
+        let directory = root_directory_path.join(directory);
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join(name), content).unwrap();
+    }
+
+    #[test]
+    fn parse() {
+        let hook = Hook::parse("info.hook", INFO_HOOK).unwrap();
+        assert_eq!(hook.when, When::PostTransaction);
+        assert_eq!(hook.triggers.len(), 1);
+        assert_eq!(
+            hook.triggers[0].operations,
+            vec![Operation::Install, Operation::Upgrade]
+        );
+        assert!(hook.needs_targets);
+        assert!(!hook.abort_on_fail);
+        let hook = Hook::parse("check.hook", CHECK_HOOK).unwrap();
+        assert_eq!(hook.triggers[0].trigger_type, TriggerType::Package);
+        assert!(hook.abort_on_fail);
+        assert!(Hook::parse("empty.hook", "").is_err());
+        assert!(Hook::parse(
+            "no-action.hook",
+            &INFO_HOOK[..INFO_HOOK.find("[Action]").unwrap()]
+        )
+        .is_err());
+        assert!(Hook::parse("unknown.hook", &INFO_HOOK.replace("Install", "Build")).is_err());
+    }
+
+    #[test]
+    fn triggers() {
+        let hook = Hook::parse("info.hook", INFO_HOOK).unwrap();
+        let mut changes = Changes::default();
+        changes.add(
+            Operation::Install,
+            "info",
+            &[
+                "usr/".to_string(),
+                "usr/share/info/dir".to_string(),
+                "usr/share/info/info.info.gz".to_string(),
+            ],
+        );
+        changes.add(
+            Operation::Remove,
+            "make",
+            &["usr/share/info/make.info.gz".to_string()],
+        );
+        assert_eq!(hook.targets(&changes), vec!["usr/share/info/info.info.gz"]);
+        let hook = Hook::parse("check.hook", CHECK_HOOK).unwrap();
+        assert!(hook.targets(&changes).is_empty());
+        changes.add(Operation::Remove, "gcc", &[]);
+        assert_eq!(hook.targets(&changes), vec!["gcc"]);
+    }
+
+    #[test]
+    fn run_hooks() {
+        let root_directory_path = DATA_DIR.join("tmp_hooks");
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+        write_hook(
+            &root_directory_path,
+            HOOKS_DIRECTORY,
+            "info.hook",
+            INFO_HOOK,
+        );
+        write_hook(
+            &root_directory_path,
+            HOOKS_DIRECTORY,
+            "check.hook",
+            CHECK_HOOK,
+        );
+        write_hook(
+            &root_directory_path,
+            HOOKS_DIRECTORY,
+            "invalid.hook",
+            "[Action]",
+        );
+        write_hook(
+            &root_directory_path,
+            HOOKS_DIRECTORY,
+            "readme.txt",
+            INFO_HOOK,
+        );
+        assert_eq!(load(&root_directory_path).len(), 2);
+        let runner = scripts::tests::runner(&root_directory_path);
+
+        let mut changes = Changes::default();
+        changes.add(
+            Operation::Upgrade,
+            "info",
+            &[
+                "usr/share/info/info.info.gz".to_string(),
+                "usr/share/info/info-stnd.info.gz".to_string(),
+            ],
+        );
+        changes.add(Operation::Remove, "gcc", &[]);
+        assert!(matches!(
+            run(&root_directory_path, &runner, When::PreTransaction, &changes),
+            Err(Error::HookFailed(name)) if name == "check.hook"
+        ));
+        assert_eq!(
+            fs::read_to_string(root_directory_path.join("check.log")).unwrap(),
+            "pre\n"
+        );
+        assert!(!root_directory_path.join("info.log").exists());
+        // PostTransaction hooks run once, with every target
+        run(
+            &root_directory_path,
+            &runner,
+            When::PostTransaction,
+            &changes,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(root_directory_path.join("info.log")).unwrap(),
+            "usr/share/info/info-stnd.info.gz\nusr/share/info/info.info.gz\n"
+        );
+
+        // an empty user hook disables the package hook
+        write_hook(&root_directory_path, USER_HOOKS_DIRECTORY, "check.hook", "");
+        run(
+            &root_directory_path,
+            &runner,
+            When::PreTransaction,
+            &changes,
+        )
+        .unwrap();
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+    }
+}
@@ -1,72 +1,199 @@
-use crate::commands::errors::Result;
-use crate::commands::packages::{Package, Packages};
+use crate::commands::catalogue::Catalogue;
+use crate::commands::errors::{Error, Result};
+use crate::commands::format::Header;
+use crate::commands::packages::{InstallReason, Installation, Package, Packages};
 use crate::commands::paths;
+use ansi_term::Color;
 use std::collections::BTreeSet;
 use std::convert::TryFrom;
 use std::fs;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::Write;
 use std::path::Path;
 
+/// Returns the installed packages, or an empty set if the file is missing or invalid
+/// and could not be restored from the backup.
 pub fn get_packages(installed_packages_file_path: &Path) -> BTreeSet<Package> {
+    read_packages(installed_packages_file_path).unwrap_or_default()
+}
+
+/// Same as get_packages, but returns the packages indexed by name.
+pub fn get_catalogue(installed_packages_file_path: &Path) -> Catalogue {
+    Catalogue::from(get_packages(installed_packages_file_path))
+}
+
+/// Reads the installed packages file.
+/// If it can't be parsed, the backup is used instead (the file is only restored from it by
+/// restore_backup, with the root directory locked).
+fn read_packages(installed_packages_file_path: &Path) -> Result<BTreeSet<Package>> {
+    match fs::metadata(installed_packages_file_path) {
+        // file doesn't exist
+        Err(_) => return Ok(BTreeSet::new()),
+        // file is empty
+        Ok(metadata) if metadata.len() == 0 => return Ok(BTreeSet::new()),
+        Ok(_) => {}
+    }
     match Packages::get_packages_from_file(installed_packages_file_path) {
-        Ok(packages) => packages,
-        Err(_) => BTreeSet::new(),
+        Ok(packages) => Ok(packages),
+        Err(err) => {
+            let backup_file_path = paths::get_backup_file_path(installed_packages_file_path);
+            let packages = Packages::get_packages_from_file(&backup_file_path).map_err(|_| err)?;
+            println!(
+                "{}",
+                Color::Red.paint("The installed packages file is invalid. Using the backup.")
+            );
+            Ok(packages)
+        }
     }
 }
 
-pub fn append_package(root_directory_path: &Path, package: &Package) -> Result<()> {
+/// Restores the installed packages file from the backup if it can't be parsed.
+/// Returns true if the file was restored.
+pub fn restore_backup(root_directory_path: &Path) -> Result<bool> {
     let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
-    let mut bytes = if let Ok(metadata) = fs::metadata(&installed_packages_file_path) {
-        if metadata.len() > 0 {
-            zstd::decode_all(File::open(&installed_packages_file_path)?)?
-        } else {
-            // file is empty
-            vec![]
-        }
-    } else {
-        // file doesn't exist
-        File::create(&installed_packages_file_path)?;
-        vec![]
-    };
-    bytes.append("\n".as_bytes().to_vec().as_mut());
-    bytes.append(String::from(package).as_bytes().to_vec().as_mut());
-    let bytes = zstd::encode_all(bytes.as_slice(), zstd::DEFAULT_COMPRESSION_LEVEL)?;
-    fs::write(&installed_packages_file_path, &bytes)?;
-    // make a copy of the installed packages file as a backup
+    match fs::metadata(&installed_packages_file_path) {
+        Ok(metadata) if metadata.len() > 0 => {}
+        _ => return Ok(false),
+    }
+    if Packages::get_packages_from_file(&installed_packages_file_path).is_ok() {
+        return Ok(false);
+    }
     let backup_file_path = paths::get_installed_packages_backup_file_path(root_directory_path);
-    let _ = fs::write(&backup_file_path, &bytes);
-    Ok(())
+    let packages = Packages::get_packages_from_file(&backup_file_path)?;
+    println!("Restoring the installed packages file from the backup.");
+    write_packages(root_directory_path, &packages)?;
+    Ok(true)
+}
+
+/// Adds a package to the installed packages file.
+/// A previously installed version of the same package is replaced.
+pub fn append_package(root_directory_path: &Path, package: &Package) -> Result<()> {
+    let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
+    let mut packages = read_packages(&installed_packages_file_path)?;
+    packages.retain(|it| it.name() != package.name());
+    packages.insert(package.clone());
+    write_packages(root_directory_path, &packages)
 }
 
+/// Replaces the installed version of a package, keeping its original install reason.
 pub fn replace_package(root_directory_path: &Path, package: &Package) -> Result<()> {
     let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
-    let input: Vec<_> = File::open(&installed_packages_file_path)
-        .map(|file| BufReader::new(file).lines())?
+    let packages = read_packages(&installed_packages_file_path)?;
+    let packages: BTreeSet<Package> = packages
+        .into_iter()
+        .map(|current| {
+            if current.matches(package.name()) {
+                let mut replacement = package.clone();
+                if let (Some(installation), Some(current_installation)) = (
+                    replacement.installation.as_mut(),
+                    current.installation.as_ref(),
+                ) {
+                    installation.reason = current_installation.reason;
+                }
+                replacement
+            } else {
+                current
+            }
+        })
         .collect();
-    let mut output = Vec::new();
-    for line in input {
-        let line = line?;
-        let current = Package::try_from(line.as_str())?;
-        if current.matches(package.name()) {
-            output.push(String::from(&current));
-        } else {
-            output.push(line);
-        }
+    write_packages(root_directory_path, &packages)
+}
+
+/// Changes the install reason of an installed package.
+pub fn set_reason(root_directory_path: &Path, name: &str, reason: InstallReason) -> Result<()> {
+    let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
+    let packages: BTreeSet<Package> = read_packages(&installed_packages_file_path)?
+        .into_iter()
+        .map(|mut package| {
+            if package.name() == name {
+                let date = package.installation.as_ref().map(|it| it.date).unwrap_or(0);
+                package.installation = Some(Installation { date, reason });
+            }
+            package
+        })
+        .collect();
+    write_packages(root_directory_path, &packages)
+}
+
+/// Removes a package from the installed packages file.
+pub fn remove_package(root_directory_path: &Path, name: &str) -> Result<()> {
+    let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
+    let mut packages = read_packages(&installed_packages_file_path)?;
+    packages.retain(|it| it.name() != name);
+    write_packages(root_directory_path, &packages)
+}
+
+/// Returns the files installed by a package, one relative path per line
+/// (directories end with a slash), or None for packages installed without a list of files.
+pub fn read_files(root_directory_path: &Path, name: &str) -> Option<Vec<String>> {
+    fs::read_to_string(paths::get_package_files_file_path(
+        root_directory_path,
+        name,
+    ))
+    .ok()
+    .map(|it| it.lines().map(|it| it.to_string()).collect())
+}
+
+pub fn write_files(root_directory_path: &Path, name: &str, files: &[String]) -> Result<()> {
+    let path = paths::get_package_files_file_path(root_directory_path, name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
     }
-    let output = output.join("\n");
-    let bytes = output.as_bytes();
-    fs::write(&installed_packages_file_path, bytes)?;
-    // make a copy of the installed packages file as a backup
-    let backup_file_path = paths::get_installed_packages_backup_file_path(root_directory_path);
-    let _ = fs::write(&backup_file_path, bytes);
+    fs::write(&path, files.join("\n"))?;
+    Ok(())
+}
+
+/// Writes the installed packages file (zstd compressed, current format).
+/// The content is written to a temporary file that is then renamed, so that the file is never
+/// partially written, and the previous version of the file becomes the backup.
+pub fn write_packages(root_directory_path: &Path, packages: &BTreeSet<Package>) -> Result<()> {
+    let mut lines = vec![String::from(&Header::create(vec![]))];
+    lines.extend(validate(packages)?);
+    let bytes = zstd::encode_all(lines.join("\n").as_bytes(), zstd::DEFAULT_COMPRESSION_LEVEL)?;
+    let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
+    let temporary_file_path = installed_packages_file_path.with_extension("tmp");
+    let mut file = File::create(&temporary_file_path)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    drop(file);
+    // the previous version becomes the backup, unless it is invalid
+    if Packages::get_packages_from_file(&installed_packages_file_path).is_ok() {
+        let backup_file_path = paths::get_installed_packages_backup_file_path(root_directory_path);
+        let temporary_backup_file_path = backup_file_path.with_extension("tmp");
+        fs::copy(&installed_packages_file_path, &temporary_backup_file_path)?;
+        fs::rename(&temporary_backup_file_path, &backup_file_path)?;
+    }
+    fs::rename(&temporary_file_path, &installed_packages_file_path)?;
     Ok(())
 }
 
+/// Returns the lines for the packages, or an error if a package has no name or version,
+/// if a package is listed more than once, or if a line can't be read back.
+fn validate(packages: &BTreeSet<Package>) -> Result<Vec<String>> {
+    let mut names = BTreeSet::new();
+    packages
+        .iter()
+        .map(|package| {
+            if package.name().is_empty()
+                || package.version.is_empty()
+                || !names.insert(package.name())
+            {
+                return Err(Error::ParseError);
+            }
+            let line = String::from(package);
+            match Package::try_from(line.as_str()) {
+                Ok(ref it) if it == package && it.repository == package.repository => Ok(line),
+                _ => Err(Error::ParseError),
+            }
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::convert::TryFrom;
+    use crate::commands::format::FORMAT_VERSION;
+    use std::io::{BufRead, BufReader};
     use std::path::PathBuf;
 
     lazy_static! {
@@ -78,6 +205,96 @@ mod tests {
         read_packages_from_file_at("installed_packages_file.zst")
     }
 
+    #[test]
+    fn migrate_packages_file() {
+        let root_directory_path = DATA_DIR.join("tmp_installed");
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+        let path = paths::get_installed_packages_file_path(&root_directory_path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::copy(DATA_DIR.join("installed_packages_file.zst"), &path).unwrap();
+        let mut package = Package::try_from("mingw64\tp3\t1.0\tzst\tany").unwrap();
+        package.metadata.description = Some("description, with comma".to_string());
+        append_package(&root_directory_path, &package).unwrap();
+        let lines: Vec<_> = BufReader::new(zstd::Decoder::new(File::open(&path).unwrap()).unwrap())
+            .lines()
+            .map(|it| it.unwrap())
+            .collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(Header::parse(&lines[0]).version, FORMAT_VERSION);
+        let packages = Packages::get_packages_from_file(&path).unwrap();
+        assert_eq!(packages.len(), 4);
+        assert_eq!(
+            packages
+                .get(&package)
+                .unwrap()
+                .metadata
+                .description
+                .as_deref(),
+            Some("description, with comma")
+        );
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+    }
+
+    #[test]
+    fn replace_and_restore_packages_file() {
+        let root_directory_path = DATA_DIR.join("tmp_replace");
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+        let path = paths::get_installed_packages_file_path(&root_directory_path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut package = Package::try_from("mingw64\tp1\t1.0").unwrap();
+        package.installation = Some(Installation {
+            date: 1,
+            reason: InstallReason::Dependency,
+        });
+        append_package(&root_directory_path, &package).unwrap();
+        append_package(&root_directory_path, &package).unwrap();
+        assert_eq!(get_packages(&path).len(), 1);
+        let mut update = Package::try_from("mingw64\tp1\t1.1").unwrap();
+        update.installation = Some(Installation {
+            date: 2,
+            reason: InstallReason::Explicit,
+        });
+        replace_package(&root_directory_path, &update).unwrap();
+        let packages = get_packages(&path);
+        assert_eq!(packages.len(), 1);
+        let installed = packages.iter().next().unwrap();
+        assert_eq!(installed.version, "1.1");
+        let installation = installed.installation.as_ref().unwrap();
+        assert_eq!(installation.date, 2);
+        assert_eq!(installation.reason, InstallReason::Dependency);
+        // the previous version is the backup
+        let backup_path = paths::get_installed_packages_backup_file_path(&root_directory_path);
+        let backup = Packages::get_packages_from_file(&backup_path).unwrap();
+        assert_eq!(backup.iter().next().unwrap().version, "1.0");
+        // the backup is used when the file is invalid, and restored only on demand
+        fs::write(&path, "invalid").unwrap();
+        assert_eq!(get_packages(&path).iter().next().unwrap().version, "1.0");
+        assert!(Packages::get_packages_from_file(&path).is_err());
+        assert!(restore_backup(&root_directory_path).unwrap());
+        assert!(!restore_backup(&root_directory_path).unwrap());
+        assert_eq!(
+            Packages::get_packages_from_file(&path)
+                .unwrap()
+                .iter()
+                .next()
+                .unwrap()
+                .version,
+            "1.0"
+        );
+        let backup = Packages::get_packages_from_file(&backup_path).unwrap();
+        assert_eq!(backup.iter().next().unwrap().version, "1.0");
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+    }
+
+    #[test]
+    fn validate_packages() {
+        let mut packages = BTreeSet::new();
+        packages.insert(Package::try_from("mingw64\tp1\t1.0").unwrap());
+        assert!(validate(&packages).is_ok());
+        packages.insert(Package::try_from("mingw64\tp1\t1.1").unwrap());
+        assert!(validate(&packages).is_err());
+    }
+
     fn read_packages_from_file_at(filename: &str) {
         let packages = Packages::get_packages_from_file(&DATA_DIR.join(filename)).unwrap();
         assert_eq!(packages.len(), 3);
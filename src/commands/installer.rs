@@ -1,27 +1,88 @@
+use crate::commands::archives;
+use crate::commands::catalogue::Catalogue;
 use crate::commands::dependencies;
+use crate::commands::dependencies::Providers;
 use crate::commands::errors::{Error, Result};
-use crate::commands::packages::Package;
+use crate::commands::hooks;
+use crate::commands::hooks::{Changes, Operation, When};
+use crate::commands::packages::{InstallReason, Installation, Package};
+use crate::commands::scripts;
+use crate::commands::scripts::Runner;
+use crate::commands::transaction::Transaction;
 use crate::commands::utils::YesNoAnswer::YES;
-use crate::commands::{available_packages, utils};
+use crate::commands::{available_packages, lock, transaction, utils};
 use crate::commands::{installed_packages, paths};
 use ansi_term::Color;
 use std::borrow::Borrow;
 use std::collections::BTreeSet;
 use std::convert::TryFrom;
+use std::fs;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read};
 use std::path::Path;
-use std::{fs, process};
 use tar::EntryType;
 
-const INSTALL: &'static str = "install";
-const UPDATE: &'static str = "update";
+/// The packages that install will install, in installation order.
+pub struct Plan {
+    /// The packages requested by the user (installed as explicit).
+    explicit: BTreeSet<Package>,
+    /// bash and its dependencies, if bash is missing.
+    bash: Vec<Package>,
+    /// info, coreutils and their dependencies, if they are missing.
+    info_coreutils: Vec<Package>,
+    /// The requested packages and their missing dependencies.
+    packages: Vec<Package>,
+}
+
+impl Plan {
+    /// All the packages of the plan, in installation order.
+    pub fn packages(&self) -> Vec<Package> {
+        self.bash
+            .iter()
+            .chain(self.info_coreutils.iter())
+            .chain(self.packages.iter())
+            .cloned()
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bash.is_empty() && self.info_coreutils.is_empty() && self.packages.is_empty()
+    }
 
-pub fn install(root_directory_path: &Path, packages: BTreeSet<Package>) {
+    fn reason(&self, package: &Package) -> InstallReason {
+        if self.explicit.contains(package) {
+            InstallReason::Explicit
+        } else {
+            InstallReason::Dependency
+        }
+    }
+}
+
+/// Installs the packages of the plan in a single transaction.
+/// If any package fails to install, all the changes are rolled back.
+pub fn install(root_directory_path: &Path, plan: &Plan, runner: &Runner) {
     let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
-    let mut installed_packages = installed_packages::get_packages(&installed_packages_file_path);
-    let available_packages_file_path = paths::get_available_packages_file_path(root_directory_path);
-    let available_packages = available_packages::get_packages(&available_packages_file_path);
+    let installed_packages = installed_packages::get_catalogue(&installed_packages_file_path);
+    let transaction = begin_transaction(root_directory_path);
+    let mut changes = Changes::default();
+    // all the archives are downloaded before anything is extracted
+    for package in plan.packages() {
+        if transaction::interrupted() {
+            abort(transaction, &package, Error::Interrupted);
+        }
+        match package_archive(&transaction, root_directory_path, &package)
+            .and_then(|it| archive_files(it.as_slice()))
+        {
+            Ok(files) => changes.add(
+                operation(&package, &installed_packages),
+                package.name(),
+                &files,
+            ),
+            Err(err) => abort(transaction, &package, err),
+        }
+    }
+    let mut transaction =
+        run_pre_transaction_hooks(transaction, root_directory_path, runner, &changes);
 
     // We need bash, info, and coreutils to run post-install scripts.
     // However, info and coreutils and/or their dependencies have post-install scripts.
@@ -29,144 +90,345 @@ pub fn install(root_directory_path: &Path, packages: BTreeSet<Package>) {
     // setup=true to skip running the scripts and flagging them as installed.
     // Then we run the installation of info and coreutils as normal.
 
-    let bash = missing_packages(vec!["bash"], &installed_packages, &available_packages);
-    if !bash.is_empty() {
-        for package in dependencies::list(bash, &installed_packages, &available_packages) {
-            if install_package(root_directory_path, &package, false).is_err() {
-                println!(
-                    "{}",
-                    Color::Red.paint(format!("Failed to install {}. Aborting.", package.name()))
-                );
-                process::exit(1);
+    for package in &plan.bash {
+        if let Err(err) = install_package(
+            &mut transaction,
+            root_directory_path,
+            runner,
+            package,
+            false,
+            plan.reason(package),
+        ) {
+            abort(transaction, package, err);
+        }
+    }
+    for setup in [true, false] {
+        for package in &plan.info_coreutils {
+            if let Err(err) = install_package(
+                &mut transaction,
+                root_directory_path,
+                runner,
+                package,
+                setup,
+                plan.reason(package),
+            ) {
+                abort(transaction, package, err);
             }
-            installed_packages.insert(package);
         }
     }
 
-    let info_coreutils = missing_packages(
-        vec!["info", "coreutils"],
+    for package in &plan.packages {
+        // another version of a requested package is already installed (e.g. downgrade)
+        let result = if let Some(installed) = installed_packages.named(package.name()) {
+            update_package(
+                &mut transaction,
+                root_directory_path,
+                runner,
+                package,
+                &installed.version,
+                plan.reason(package) == InstallReason::Explicit,
+            )
+        } else {
+            install_package(
+                &mut transaction,
+                root_directory_path,
+                runner,
+                package,
+                false,
+                plan.reason(package),
+            )
+        };
+        if let Err(err) = result {
+            abort(transaction, package, err);
+        }
+    }
+    commit_transaction(transaction);
+    run_post_transaction_hooks(root_directory_path, runner, &changes);
+}
+
+/// Resolves the packages to install and their missing dependencies, including bash, info and
+/// coreutils if they are missing. If with_optional is true, the optional dependencies of the
+/// packages are installed as well.
+pub fn plan(
+    root_directory_path: &Path,
+    packages: BTreeSet<Package>,
+    with_optional: bool,
+    providers: &Providers,
+) -> Plan {
+    let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
+    let mut installed_packages = installed_packages::get_catalogue(&installed_packages_file_path);
+    let available_packages_file_path = paths::get_available_packages_file_path(root_directory_path);
+    let available_packages = available_packages::get_catalogue(&available_packages_file_path);
+    let mut setup = [Vec::new(), Vec::new()];
+    for (names, plan) in [vec!["bash"], vec!["info", "coreutils"]]
+        .into_iter()
+        .zip(setup.iter_mut())
+    {
+        let missing = missing_packages(names, &installed_packages, &available_packages);
+        if !missing.is_empty() {
+            for package in
+                dependencies::list(missing, &installed_packages, &available_packages, providers)
+            {
+                installed_packages.insert(package.clone());
+                plan.push(package);
+            }
+        }
+    }
+    let [bash, info_coreutils] = setup;
+    let packages_to_install = dependencies::list(
+        roots(
+            &packages,
+            with_optional,
+            &installed_packages,
+            &available_packages,
+            providers,
+        ),
         &installed_packages,
         &available_packages,
+        providers,
     );
-    if !info_coreutils.is_empty() {
-        for package in dependencies::list(
-            info_coreutils.clone(),
-            &installed_packages,
-            &available_packages,
-        ) {
-            if install_package(root_directory_path, &package, true).is_err() {
-                println!(
-                    "{}",
-                    Color::Red.paint(format!("Failed to install {}. Aborting.", package.name()))
-                );
-                process::exit(1);
-            }
+    Plan {
+        explicit: packages,
+        bash,
+        info_coreutils,
+        packages: packages_to_install,
+    }
+}
+
+/// The packages to install, followed by their optional dependencies if with_optional is true.
+fn roots<'a>(
+    packages: &'a BTreeSet<Package>,
+    with_optional: bool,
+    installed_packages: &'a Catalogue,
+    available_packages: &'a Catalogue,
+    providers: &Providers,
+) -> Vec<&'a Package> {
+    let mut roots: Vec<&Package> = packages.iter().collect();
+    if with_optional {
+        let optional =
+            dependencies::optional(&roots, installed_packages, available_packages, providers);
+        roots.extend(optional);
+    }
+    roots
+}
+
+/// Updates the packages in the specified order, in a single transaction.
+/// Packages that are not installed yet (new dependencies) are installed.
+/// If explicit is true (packages named by the user), the packages are marked as explicitly
+/// installed, otherwise their install reason is kept (and the database entry of a package
+/// reinstalled with the same version isn't changed).
+pub fn update(root_directory_path: &Path, packages: Vec<Package>, explicit: bool, runner: &Runner) {
+    let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
+    let installed_packages = installed_packages::get_catalogue(&installed_packages_file_path);
+    let transaction = begin_transaction(root_directory_path);
+    let mut changes = Changes::default();
+    // all the archives are downloaded before anything is extracted
+    for package in &packages {
+        if transaction::interrupted() {
+            abort(transaction, package, Error::Interrupted);
         }
-        for package in dependencies::list(info_coreutils, &installed_packages, &available_packages)
+        match package_archive(&transaction, root_directory_path, package)
+            .and_then(|it| archive_files(it.as_slice()))
         {
-            if install_package(root_directory_path, &package, false).is_err() {
-                println!(
-                    "{}",
-                    Color::Red.paint(format!("Failed to install {}. Aborting.", package.name()))
-                );
-                process::exit(1);
-            }
-            installed_packages.insert(package);
+            Ok(files) => changes.add(
+                operation(package, &installed_packages),
+                package.name(),
+                &files,
+            ),
+            Err(err) => abort(transaction, package, err),
+        }
+    }
+    let mut transaction =
+        run_pre_transaction_hooks(transaction, root_directory_path, runner, &changes);
+    for package in packages {
+        let result = if let Some(installed) = installed_packages.named(package.name()) {
+            update_package(
+                &mut transaction,
+                root_directory_path,
+                runner,
+                &package,
+                &installed.version,
+                explicit,
+            )
+        } else {
+            install_package(
+                &mut transaction,
+                root_directory_path,
+                runner,
+                &package,
+                false,
+                if explicit {
+                    InstallReason::Explicit
+                } else {
+                    InstallReason::Dependency
+                },
+            )
+        };
+        if let Err(err) = result {
+            abort(transaction, &package, err);
         }
     }
+    commit_transaction(transaction);
+    run_post_transaction_hooks(root_directory_path, runner, &changes);
+}
 
-    for package in dependencies::list(
-        packages.iter().collect(),
-        &installed_packages,
-        &available_packages,
-    ) {
-        if install_package(root_directory_path, &package, false).is_err() {
+/// Upgrade if another version of the package is installed, Install otherwise.
+fn operation(package: &Package, installed_packages: &Catalogue) -> Operation {
+    if installed_packages.named(package.name()).is_some() {
+        Operation::Upgrade
+    } else {
+        Operation::Install
+    }
+}
+
+/// Runs the PreTransaction hooks, before any change is made.
+/// If a hook with AbortOnFail fails, the transaction is rolled back.
+fn run_pre_transaction_hooks(
+    transaction: Transaction,
+    root_directory_path: &Path,
+    runner: &Runner,
+    changes: &Changes,
+) -> Transaction {
+    if let Err(Error::HookFailed(name)) =
+        hooks::run(root_directory_path, runner, When::PreTransaction, changes)
+    {
+        println!("{}", Color::Red.paint(format!("Hook {} failed.", name)));
+        roll_back(transaction);
+    }
+    transaction
+}
+
+/// Runs the PostTransaction hooks, once the changes are committed.
+fn run_post_transaction_hooks(root_directory_path: &Path, runner: &Runner, changes: &Changes) {
+    // only PreTransaction hooks can fail the transaction
+    let _ = hooks::run(root_directory_path, runner, When::PostTransaction, changes);
+}
+
+fn begin_transaction(root_directory_path: &Path) -> Transaction {
+    match Transaction::begin(root_directory_path) {
+        Ok(transaction) => transaction,
+        Err(_) => {
             println!(
                 "{}",
-                Color::Red.paint(format!("Failed to install {}. Aborting.", package.name()))
+                Color::Red.paint("Failed to start the transaction. Aborting.")
             );
-            process::exit(1);
+            lock::exit(1);
         }
     }
 }
 
-pub fn update(root_directory_path: &Path, packages: BTreeSet<Package>) {
-    let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
-    let installed_packages = installed_packages::get_packages(&installed_packages_file_path);
-    for package in packages {
-        if !installed_packages.contains(&package) {
-            if update_package(root_directory_path, &package).is_err() {
-                println!(
-                    "{}",
-                    Color::Red.paint(format!("Failed to update {}. Aborting.", package.name()))
-                );
-                process::exit(1);
-            }
-        }
+fn commit_transaction(transaction: Transaction) {
+    if transaction.commit().is_err() {
+        println!(
+            "{}",
+            Color::Red.paint("Failed to remove the transaction backups.")
+        );
     }
 }
 
+/// Reports the package that failed and rolls back the transaction.
+fn abort(transaction: Transaction, package: &Package, err: Error) -> ! {
+    match err {
+        Error::Interrupted => println!("{}", Color::Red.paint("Interrupted.")),
+        _ => println!(
+            "{}",
+            Color::Red.paint(format!("Failed to install {}.", package.name()))
+        ),
+    }
+    roll_back(transaction);
+}
+
+/// Rolls back all the changes made by the transaction and exits.
+fn roll_back(transaction: Transaction) -> ! {
+    println!("Rolling back the changes.");
+    match transaction.rollback() {
+        Ok(_) => println!("The changes were rolled back."),
+        Err(_) => println!(
+            "{}",
+            Color::Red.paint("Failed to roll back the changes. Run the recover command to retry.")
+        ),
+    }
+    lock::exit(1);
+}
+
 fn missing_packages<'a>(
     packages: Vec<&str>,
-    installed_packages: &BTreeSet<Package>,
-    available_packages: &'a BTreeSet<Package>,
+    installed_packages: &Catalogue,
+    available_packages: &'a Catalogue,
 ) -> Vec<&'a Package> {
     packages
         .into_iter()
-        .filter_map(
-            |name| match installed_packages.iter().find(|&it| it.matches(name)) {
-                Some(_) => None,
-                None => match available_packages::latest_version(name, available_packages) {
-                    Some(it) => Some(it),
-                    None => {
-                        println!(
-                            "{}",
-                            Color::Red
-                                .paint(&format!("Could not find {} package. Aborting.", name))
-                        );
-                        process::exit(1);
-                    }
-                },
+        .filter_map(|name| match installed_packages.latest_version(name) {
+            Some(_) => None,
+            None => match available_packages.latest_version(name) {
+                Some(it) => Some(it),
+                None => {
+                    println!(
+                        "{}",
+                        Color::Red.paint(format!("Could not find {} package. Aborting.", name))
+                    );
+                    lock::exit(1);
+                }
             },
-        )
+        })
         .collect()
 }
 
-fn update_package(root_directory_path: &Path, package: &Package) -> Result<()> {
+fn update_package(
+    transaction: &mut Transaction,
+    root_directory_path: &Path,
+    runner: &Runner,
+    package: &Package,
+    old_version: &str,
+    explicit: bool,
+) -> Result<()> {
+    if transaction::interrupted() {
+        return Err(Error::Interrupted);
+    }
     println!(
         "{} {}",
         Color::Purple.paint(package.name()),
         package.version
     );
-    let pending_installation_file_path =
-        paths::get_pending_installation_file_path(root_directory_path);
-    // first update the pending installation file so that we can retry
-    // the update if we crash or the program is interrupted.
-    fs::write(
-        &pending_installation_file_path,
-        format!("{}\n{}", UPDATE, String::from(package)).as_str(),
+    let bytes = package_archive(transaction, root_directory_path, package)?;
+    let versions = [package.version.as_str(), old_version];
+    // the functions of the new script are used
+    scripts::save(
+        transaction,
+        root_directory_path,
+        package.name(),
+        install_script(bytes.as_slice())?.as_deref(),
     )?;
-    let bytes = download_package_archive(package)?;
-    let compression = package.compression.unwrap();
-    let bytes = match compression.decompress(bytes.as_slice()) {
-        Ok(bytes) => bytes,
-        Err(err) => {
-            println!(
-                "{}",
-                Color::Red.paint(format!(
-                    "Failed to decompress {} archive for {}",
-                    compression.extension(),
-                    package.name()
-                ))
-            );
-            return Err(err);
-        }
-    };
-    extract_package(root_directory_path, bytes.as_slice(), false)?;
+    runner.run_function(
+        root_directory_path,
+        package.name(),
+        "pre_upgrade",
+        &versions,
+    )?;
+    extract_package(transaction, root_directory_path, bytes.as_slice(), false)?;
+    save_package_files(transaction, root_directory_path, package, bytes.as_slice())?;
     // update the installed packages file
-    installed_packages::replace_package(root_directory_path, package)?;
-    // remove the pending installation file
-    rm_rf::remove(&pending_installation_file_path).map_err(|_| Error::RemoveError)?;
+    // (the original install reason is kept unless the package was named by the user,
+    // a reinstalled package keeps its entry)
+    if explicit || package.version != old_version {
+        let mut package = package.clone();
+        package.installation = Some(Installation {
+            date: utils::now(),
+            reason: InstallReason::Explicit,
+        });
+        if explicit {
+            installed_packages::append_package(root_directory_path, &package)?;
+        } else {
+            installed_packages::replace_package(root_directory_path, &package)?;
+        }
+    }
+    // the files are in place, a failure is reported but doesn't undo the upgrade
+    let _ = runner.run_function(
+        root_directory_path,
+        package.name(),
+        "post_upgrade",
+        &versions,
+    );
     Ok(())
 }
 
@@ -176,7 +438,17 @@ fn update_package(root_directory_path: &Path, package: &Package) -> Result<()> {
 // without running the install scripts (setup arg to true)
 // and without flagging those packages as installed.
 // After that, we can reinstall those packages as normal.
-fn install_package(root_directory_path: &Path, package: &Package, setup: bool) -> Result<()> {
+fn install_package(
+    transaction: &mut Transaction,
+    root_directory_path: &Path,
+    runner: &Runner,
+    package: &Package,
+    setup: bool,
+    reason: InstallReason,
+) -> Result<()> {
+    if transaction::interrupted() {
+        return Err(Error::Interrupted);
+    }
     if !setup {
         println!(
             "{} {}",
@@ -184,17 +456,209 @@ fn install_package(root_directory_path: &Path, package: &Package, setup: bool) -
             package.version
         );
     }
-    let pending_installation_file_path =
-        paths::get_pending_installation_file_path(root_directory_path);
+    let bytes = package_archive(transaction, root_directory_path, package)?;
+    let versions = [package.version.as_str()];
     if !setup {
-        // first update the pending installation file so that we can retry
-        // the installation if we crash or the program is interrupted.
-        fs::write(
-            &pending_installation_file_path,
-            format!("{}\n{}", INSTALL, String::from(package)).as_str(),
+        scripts::save(
+            transaction,
+            root_directory_path,
+            package.name(),
+            install_script(bytes.as_slice())?.as_deref(),
+        )?;
+        runner.run_function(
+            root_directory_path,
+            package.name(),
+            "pre_install",
+            &versions,
         )?;
     }
-    let bytes = download_package_archive(package)?;
+    extract_package(transaction, root_directory_path, bytes.as_slice(), setup)?;
+    if !setup {
+        save_package_files(transaction, root_directory_path, package, bytes.as_slice())?;
+        // update the installed packages file
+        let mut package = package.clone();
+        package.installation = Some(Installation {
+            date: utils::now(),
+            reason,
+        });
+        installed_packages::append_package(root_directory_path, &package)?;
+        // the files are in place, a failure is reported but doesn't undo the installation
+        let _ = runner.run_function(
+            root_directory_path,
+            package.name(),
+            "post_install",
+            &versions,
+        );
+    }
+    Ok(())
+}
+
+/// Removes the packages and their files in a single transaction.
+/// If any package fails to be removed, all the changes are rolled back.
+pub fn remove(root_directory_path: &Path, packages: Vec<Package>, runner: &Runner) {
+    let transaction = begin_transaction(root_directory_path);
+    let mut changes = Changes::default();
+    for package in &packages {
+        if transaction::interrupted() {
+            abort(transaction, package, Error::Interrupted);
+        }
+        match package_files(&transaction, root_directory_path, package) {
+            Ok(files) => changes.add(Operation::Remove, package.name(), &files),
+            Err(err) => abort(transaction, package, err),
+        }
+    }
+    let mut transaction =
+        run_pre_transaction_hooks(transaction, root_directory_path, runner, &changes);
+    for package in &packages {
+        if let Err(err) = remove_package(&mut transaction, root_directory_path, runner, package) {
+            abort(transaction, package, err);
+        }
+    }
+    commit_transaction(transaction);
+    run_post_transaction_hooks(root_directory_path, runner, &changes);
+}
+
+fn remove_package(
+    transaction: &mut Transaction,
+    root_directory_path: &Path,
+    runner: &Runner,
+    package: &Package,
+) -> Result<()> {
+    if transaction::interrupted() {
+        return Err(Error::Interrupted);
+    }
+    println!(
+        "{} {}",
+        Color::Purple.paint(package.name()),
+        package.version
+    );
+    let files = package_files(transaction, root_directory_path, package)?;
+    let versions = [package.version.as_str()];
+    runner.run_function(root_directory_path, package.name(), "pre_remove", &versions)?;
+    // files and links first, then the directories that are empty, deepest first
+    let mut directories = Vec::new();
+    for file in &files {
+        let path = root_directory_path.join(file);
+        if file.ends_with('/') {
+            directories.push(path);
+        } else if let Ok(metadata) = fs::symlink_metadata(&path) {
+            if !metadata.is_dir() {
+                transaction.record(&path)?;
+                // junctions can only be removed as directories
+                fs::remove_file(&path).or_else(|_| fs::remove_dir(&path))?;
+            }
+        }
+    }
+    directories.sort_by(|a, b| b.cmp(a));
+    for directory in directories {
+        // junctions created by the installation root are not removed
+        if fs::symlink_metadata(&directory)
+            .map(|it| it.is_dir())
+            .unwrap_or(false)
+        {
+            transaction.record_removed_directory(&directory)?;
+            let _ = fs::remove_dir(&directory);
+        }
+    }
+    let files_path = paths::get_package_files_file_path(root_directory_path, package.name());
+    if files_path.exists() {
+        transaction.record(&files_path)?;
+        fs::remove_file(&files_path)?;
+    }
+    installed_packages::remove_package(root_directory_path, package.name())?;
+    let _ = runner.run_function(
+        root_directory_path,
+        package.name(),
+        "post_remove",
+        &versions,
+    );
+    scripts::save(transaction, root_directory_path, package.name(), None)
+}
+
+/// Returns the files installed by the package.
+fn package_files(
+    transaction: &Transaction,
+    root_directory_path: &Path,
+    package: &Package,
+) -> Result<Vec<String>> {
+    match installed_packages::read_files(root_directory_path, package.name()) {
+        Some(files) => Ok(files),
+        // packages installed by older versions don't have a list of files
+        None => {
+            archive_files(package_archive(transaction, root_directory_path, package)?.as_slice())
+        }
+    }
+}
+
+/// Saves the list of the files of the package, so that they can be removed later.
+fn save_package_files(
+    transaction: &mut Transaction,
+    root_directory_path: &Path,
+    package: &Package,
+    uncompressed_package_archive: &[u8],
+) -> Result<()> {
+    let files = archive_files(uncompressed_package_archive)?;
+    transaction.record(&paths::get_package_files_file_path(
+        root_directory_path,
+        package.name(),
+    ))?;
+    installed_packages::write_files(root_directory_path, package.name(), &files)
+}
+
+/// Returns the install script (.INSTALL) of the package archive if it has one.
+fn install_script(uncompressed_package_archive: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut archive = tar::Archive::new(uncompressed_package_archive);
+    let entries = archive.entries().map_err(|_| Error::DecompressionError)?;
+    for mut entry in entries.filter_map(|it| it.ok()) {
+        if entry.path().map(|it| it.to_string_lossy() == ".INSTALL")? {
+            let mut script = Vec::new();
+            entry.read_to_end(&mut script)?;
+            return Ok(Some(script));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the relative paths of the entries of the package archive, without the metadata files.
+/// Directories end with a slash.
+fn archive_files(uncompressed_package_archive: &[u8]) -> Result<Vec<String>> {
+    let mut archive = tar::Archive::new(uncompressed_package_archive);
+    let entries = archive.entries().map_err(|_| Error::DecompressionError)?;
+    Ok(entries
+        .filter_map(|it| it.ok())
+        .filter_map(|entry| {
+            let name = entry.path().ok()?.to_string_lossy().to_string();
+            match name.as_str() {
+                ".BUILDINFO" | ".MTREE" | ".PKGINFO" | ".INSTALL" => None,
+                it if it.contains("..") || Path::new(it).is_absolute() => None,
+                it => match entry.header().entry_type() {
+                    EntryType::Directory if !it.ends_with('/') => Some(format!("{}/", it)),
+                    _ => Some(it.to_string()),
+                },
+            }
+        })
+        .collect())
+}
+
+/// Returns the uncompressed archive staged by the transaction, or stages it if it is missing.
+/// The archive is taken from the archive cache, or downloaded and saved to the cache.
+fn package_archive(
+    transaction: &Transaction,
+    root_directory_path: &Path,
+    package: &Package,
+) -> Result<Vec<u8>> {
+    if let Ok(bytes) = transaction.staged(package) {
+        return Ok(bytes);
+    }
+    let bytes = match archives::cached_archive(root_directory_path, package) {
+        Some(bytes) => bytes,
+        None => {
+            let bytes = download_package_archive(package)?;
+            // the installation can go on without the cached copy
+            let _ = archives::cache_archive(root_directory_path, package, &bytes);
+            bytes
+        }
+    };
     let compression = package.compression.unwrap();
     let bytes = match compression.decompress(bytes.as_slice()) {
         Ok(bytes) => bytes,
@@ -210,14 +674,8 @@ fn install_package(root_directory_path: &Path, package: &Package, setup: bool) -
             return Err(err);
         }
     };
-    extract_package(root_directory_path, bytes.as_slice(), setup)?;
-    if !setup {
-        // update the installed packages file
-        installed_packages::append_package(root_directory_path, package)?;
-        // remove the pending installation file
-        rm_rf::remove(&pending_installation_file_path).map_err(|_| Error::RemoveError)?;
-    }
-    Ok(())
+    transaction.stage(package, bytes.as_slice())?;
+    Ok(bytes)
 }
 
 fn download_package_archive(package: &Package) -> Result<Vec<u8>> {
@@ -239,6 +697,7 @@ fn download_package_archive(package: &Package) -> Result<Vec<u8>> {
 }
 
 fn extract_package(
+    transaction: &mut Transaction,
     root_directory_path: &Path,
     uncompressed_package_archive: &[u8],
     setup: bool,
@@ -247,7 +706,7 @@ fn extract_package(
     // regular files
     match tar::Archive::new(uncompressed_package_archive).entries() {
         Ok(entries) => {
-            entries.filter_map(|it| it.ok()).for_each(|mut entry| {
+            for mut entry in entries.filter_map(|it| it.ok()) {
                 match entry.path() {
                     Ok(name) => {
                         if name.is_relative() {
@@ -255,17 +714,13 @@ fn extract_package(
                                 ".BUILDINFO" => {}
                                 ".MTREE" => {}
                                 ".PKGINFO" => {}
-                                ".INSTALL" => {
-                                    if !setup {
-                                        // install script that we will run later
-                                        let path = root_directory_path.join(name);
-                                        entry.unpack(&path).unwrap();
-                                    }
-                                }
+                                // install script, see scripts.rs
+                                ".INSTALL" => {}
                                 name => {
                                     if !name.contains("..") {
                                         // println!("{}", &name.to_string());
                                         let path = root_directory_path.join(name);
+                                        transaction.record(&path)?;
                                         path.parent().and_then(|parent| {
                                             std::fs::create_dir_all(parent).ok()
                                         });
@@ -313,14 +768,14 @@ fn extract_package(
                     }
                     Err(_) => println!("{}", &Color::Red.paint("Invalid path in tar archive")),
                 };
-            });
+            }
         }
         Err(_) => return Err(Error::DecompressionError),
     }
     // links
     match tar::Archive::new(uncompressed_package_archive).entries() {
         Ok(entries) => {
-            entries.filter_map(|it| it.ok()).for_each(|entry| {
+            for entry in entries.filter_map(|it| it.ok()) {
                 match entry.path() {
                     Ok(name) => {
                         if name.is_relative() {
@@ -334,6 +789,7 @@ fn extract_package(
                                         let path = root_directory_path.join(name);
                                         if match entry.header().entry_type() {
                                             EntryType::Link | EntryType::Symlink => {
+                                                transaction.record(&path)?;
                                                 rm_rf::ensure_removed(&path).ok().and_then(|_| {
                                                     entry
                                                         .link_name()
@@ -386,43 +842,10 @@ fn extract_package(
                     }
                     Err(_) => println!("{}", &Color::Red.paint("Invalid path in tar archive")),
                 };
-            });
+            }
         }
         Err(_) => return Err(Error::DecompressionError),
     }
-    if !setup {
-        // run the install script
-        let path = root_directory_path.join(".INSTALL");
-        if path.exists() {
-            let bash_env_path = root_directory_path
-                .join("usr")
-                .join("bin")
-                .to_string_lossy()
-                .to_string();
-            match std::process::Command::new(
-                &root_directory_path.join("usr").join("bin").join("bash.exe"),
-            )
-            .current_dir(root_directory_path)
-            .args(&["-c", "source /.INSTALL && (declare -F -f post_install && post_install) || (declare -F -f post_upgrade && post_upgrade)"])    
-            .env("PATH", &bash_env_path)
-            .output()
-            {
-                Ok(output) => {
-                    if !output.stderr.is_empty() {
-                        println!(
-                            "{}",
-                            Color::Red.paint(String::from_utf8_lossy(&output.stderr))
-                        );
-                    }
-                }
-                Err(err) => {
-                    println!("{}", Color::Red.paint("Failed to run install script"));
-                    println!("{:?}", err);
-                }
-            };
-            std::fs::remove_file(&path).unwrap();
-        }
-    }
     Ok(())
 }
 
@@ -436,59 +859,164 @@ fn entry_type_name(entry_type: &EntryType) -> String {
     }
 }
 
-pub fn check_for_pending_installation(root_directory_path: &Path, no_prompt: bool) {
+/// Offers to roll back a transaction that was interrupted (e.g. by a crash or a power failure).
+/// Mutating commands cannot proceed until the previous transaction is rolled back.
+/// A dry run only reports the interrupted transaction and leaves it as is.
+pub fn check_for_pending_installation(root_directory_path: &Path, no_prompt: bool, dry_run: bool) {
+    if dry_run {
+        if transaction::exists(root_directory_path) {
+            println!("The previous installation did not finish successfully.");
+            println!("Its changes will be rolled back before the installation.");
+        }
+        return;
+    }
+    // pending installation file left by older versions
     let pending_installation_file_path =
         paths::get_pending_installation_file_path(root_directory_path);
     if pending_installation_file_path.exists() {
-        if let Ok(mut lines) =
-            File::open(&pending_installation_file_path).map(|file| BufReader::new(file).lines())
+        if let Some(package) = File::open(&pending_installation_file_path)
+            .ok()
+            .and_then(|file| BufReader::new(file).lines().nth(1))
+            .and_then(|it| it.ok())
+            .and_then(|ref it| Package::try_from(it.as_str()).ok())
         {
-            let command = lines.next().and_then(|it| it.ok());
-            let package = lines
-                .next()
-                .and_then(|it| it.ok())
-                .and_then(|ref it| Package::try_from(it.as_str()).ok());
-            if package.is_some() && command.is_some() {
-                let command = command.unwrap();
-                let package = package.unwrap();
-                match command.as_str() {
-                    INSTALL => {
-                        println!(
-                            "Installation of {} did not finish successfully.",
-                            Color::Purple.paint(package.name())
-                        );
-                        let answer = utils::yes_or_no("Retry?", YES, no_prompt, Some("Retrying."));
-                        match answer {
-                            YES => {
-                                let mut packages = BTreeSet::new();
-                                packages.insert(package);
-                                install(root_directory_path, packages);
-                            }
-                            _ => {
-                                let _ = rm_rf::remove(&pending_installation_file_path);
-                            }
-                        }
-                    }
-                    UPDATE => {
-                        println!(
-                            "Update of {} did not finish successfully.",
-                            Color::Purple.paint(package.name())
-                        );
-                        let answer = utils::yes_or_no("Retry?", YES, no_prompt, Some("Retrying."));
-                        match answer {
-                            YES => {
-                                let mut packages = BTreeSet::new();
-                                packages.insert(package);
-                                update(root_directory_path, packages);
-                            }
-                            _ => {
-                                let _ = rm_rf::remove(&pending_installation_file_path);
-                            }
-                        }
-                    }
-                    _ => {}
-                }
+            println!(
+                "Installation of {} did not finish successfully.",
+                Color::Purple.paint(package.name())
+            );
+        }
+        let _ = rm_rf::remove(&pending_installation_file_path);
+    }
+    if transaction::exists(root_directory_path) {
+        println!("The previous installation did not finish successfully.");
+        match utils::yes_or_no(
+            "Roll back the changes?",
+            YES,
+            no_prompt,
+            Some("Rolling back the changes."),
+        ) {
+            YES => recover(root_directory_path),
+            _ => {
+                println!(
+                    "{}",
+                    Color::Red.paint("The changes need to be rolled back first. Aborting.")
+                );
+                lock::exit(1);
             }
         }
     }
 }
+
+/// Rolls back the changes of an interrupted transaction.
+pub fn recover(root_directory_path: &Path) {
+    if !transaction::exists(root_directory_path) {
+        println!("Nothing to recover.");
+        return;
+    }
+    match transaction::rollback(root_directory_path) {
+        Ok(_) => println!("The changes were rolled back."),
+        Err(_) => {
+            println!(
+                "{}",
+                Color::Red
+                    .paint("Failed to roll back the changes. Run the recover command to retry.")
+            );
+            lock::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::scripts;
+    use std::path::PathBuf;
+
+    lazy_static! {
+        static ref DATA_DIR: PathBuf = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("data");
+    }
+
+    const SCRIPT: &str = r#"
+pre_install() { echo "pre_install $*" >> calls.log; }
+post_install() { echo "post_install $*" >> calls.log; }
+pre_upgrade() { echo "pre_upgrade $*" >> calls.log; }
+post_upgrade() { echo "post_upgrade $*" >> calls.log; }
+pre_remove() { echo "pre_remove $*" >> calls.log; }
+post_remove() { echo "post_remove $*" >> calls.log; }
+"#;
+
+    /// Saves an archive of the test package to the archive cache, so that it isn't downloaded.
+    fn cache_package(root_directory_path: &Path, version: &str) -> Package {
+        let package =
+            Package::try_from(format!("msys\ttest\t{}\tzst\tx86_64", version).as_str()).unwrap();
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, content) in [(".INSTALL", SCRIPT), ("usr/share/test/version", version)] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        let archive = zstd::encode_all(builder.into_inner().unwrap().as_slice(), 0).unwrap();
+        archives::cache_archive(root_directory_path, &package, &archive).unwrap();
+        package
+    }
+
+    #[test]
+    fn lifecycle() {
+        let root_directory_path = DATA_DIR.join("tmp_lifecycle");
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+        let installed_packages_file_path =
+            paths::get_installed_packages_file_path(&root_directory_path);
+        fs::create_dir_all(installed_packages_file_path.parent().unwrap()).unwrap();
+        let runner = scripts::tests::runner(&root_directory_path);
+        let file_path = root_directory_path.join("usr/share/test/version");
+
+        let installed = || {
+            installed_packages::get_catalogue(&installed_packages_file_path)
+                .named("test")
+                .cloned()
+                .unwrap()
+        };
+        let reason = || installed().installation.unwrap().reason;
+
+        let package = cache_package(&root_directory_path, "1.0-1");
+        update(&root_directory_path, vec![package], false, &runner);
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "1.0-1");
+        assert_eq!(reason(), InstallReason::Dependency);
+        let package = cache_package(&root_directory_path, "2.0-1");
+        update(&root_directory_path, vec![package.clone()], false, &runner);
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "2.0-1");
+        assert_eq!(installed().version, "2.0-1");
+        assert_eq!(reason(), InstallReason::Dependency);
+        // reinstalled
+        fs::remove_file(&file_path).unwrap();
+        let before = installed();
+        update(&root_directory_path, vec![before.clone()], false, &runner);
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "2.0-1");
+        assert_eq!(reason(), InstallReason::Dependency);
+        assert_eq!(
+            installed().installation.unwrap().date,
+            before.installation.unwrap().date
+        );
+        // named by the user
+        update(&root_directory_path, vec![package.clone()], true, &runner);
+        assert_eq!(reason(), InstallReason::Explicit);
+        remove(&root_directory_path, vec![package], &runner);
+        assert!(!file_path.exists());
+        assert!(installed_packages::get_packages(&installed_packages_file_path).is_empty());
+        assert!(!paths::get_package_script_file_path(&root_directory_path, "test").exists());
+
+        assert_eq!(
+            fs::read_to_string(root_directory_path.join("calls.log")).unwrap(),
+            "pre_install 1.0-1\npost_install 1.0-1\n\
+             pre_upgrade 2.0-1 1.0-1\npost_upgrade 2.0-1 1.0-1\n\
+             pre_upgrade 2.0-1 2.0-1\npost_upgrade 2.0-1 2.0-1\n\
+             pre_upgrade 2.0-1 2.0-1\npost_upgrade 2.0-1 2.0-1\n\
+             pre_remove 2.0-1\npost_remove 2.0-1\n"
+        );
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+use crate::commands::errors::{Error, Result};
+use crate::commands::paths;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+
+lazy_static! {
+    /// Lock files held by this process, so that they can be removed before exiting.
+    static ref HELD: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+}
+
+/// Exclusive lock on a root directory, held by commands that modify it.
+/// The lock file (var/local/packages/lock) contains the PID of the process holding the lock,
+/// and is removed when the lock is dropped, or by exit.
+/// A lock file left by a process that is no longer running is stale and is replaced.
+/// A lock file that can't be read is considered held, unless the lock is broken.
+pub struct Lock {
+    path: PathBuf,
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        release(&self.path);
+    }
+}
+
+fn release(path: &Path) {
+    HELD.lock()
+        .unwrap_or_else(|it| it.into_inner())
+        .remove(path);
+    // the lock may have been broken and taken by another process
+    if read_pid(path) == Some(process::id()) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Releases the locks held by this process and exits.
+/// process::exit doesn't run destructors, so commands holding a lock should exit with this
+/// function instead.
+pub fn exit(code: i32) -> ! {
+    let held: Vec<_> = HELD
+        .lock()
+        .unwrap_or_else(|it| it.into_inner())
+        .iter()
+        .cloned()
+        .collect();
+    for path in held {
+        release(&path);
+    }
+    process::exit(code)
+}
+
+fn read_pid(path: &Path) -> Option<u32> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|it| it.trim().parse::<u32>().ok())
+}
+
+/// Takes the lock on the root directory.
+/// If break_lock is true, the lock is taken even if the process holding it seems to be running.
+pub fn acquire(root_directory_path: &Path, break_lock: bool) -> Result<Lock> {
+    let path = paths::get_lock_file_path(root_directory_path);
+    // the lock file is written under another name first and then linked into place, so that
+    // other processes never see a lock file without the PID
+    let pending = path.with_extension(process::id().to_string());
+    fs::write(&pending, process::id().to_string())?;
+    let linked = loop {
+        match fs::hard_link(&pending, &path) {
+            Ok(_) => break Ok(()),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                let pid = read_pid(&path);
+                match pid {
+                    Some(pid) if !break_lock && is_running(pid) => {
+                        break Err(Error::Locked(Some(pid)))
+                    }
+                    None if !break_lock => break Err(Error::Locked(None)),
+                    _ => {
+                        if let Err(err) = remove_stale(&path, pid, break_lock) {
+                            break Err(err);
+                        }
+                    }
+                }
+            }
+            Err(err) => break Err(Error::IOError(err)),
+        }
+    };
+    let _ = fs::remove_file(&pending);
+    linked?;
+    HELD.lock()
+        .unwrap_or_else(|it| it.into_inner())
+        .insert(path.clone());
+    Ok(Lock { path })
+}
+
+/// Removes a stale lock file. The file is moved away first, and moved back if it turns out that
+/// another process replaced the stale lock with its own in the meantime.
+fn remove_stale(path: &Path, pid: Option<u32>, break_lock: bool) -> Result<()> {
+    let stale = path.with_extension(format!("stale.{}", process::id()));
+    match fs::rename(path, &stale) {
+        Ok(_) => {}
+        // already removed by another process
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(Error::IOError(err)),
+    }
+    if !break_lock && read_pid(&stale) != pid {
+        let _ = fs::hard_link(&stale, path);
+    }
+    fs::remove_file(&stale)?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn is_running(pid: u32) -> bool {
+    process::Command::new("tasklist")
+        .args(&["/FI", &format!("PID eq {}", pid), "/NH", "/FO", "CSV"])
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).contains(&format!("\"{}\"", pid)))
+        // assume that it is running if we can't tell
+        .unwrap_or(true)
+}
+
+#[cfg(not(windows))]
+fn is_running(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    lazy_static! {
+        static ref DATA_DIR: PathBuf = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("data");
+    }
+
+    #[test]
+    fn locks() {
+        let root_directory_path = DATA_DIR.join("tmp_lock");
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+        let path = paths::get_lock_file_path(&root_directory_path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+        let lock = acquire(&root_directory_path, false).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            process::id().to_string()
+        );
+        match acquire(&root_directory_path, false) {
+            Err(Error::Locked(pid)) => assert_eq!(pid, Some(process::id())),
+            _ => panic!("expected the root directory to be locked"),
+        }
+        let broken = acquire(&root_directory_path, true).unwrap();
+        drop(lock);
+        drop(broken);
+        assert!(!path.exists());
+
+        // lock file without a PID
+        fs::write(&path, "").unwrap();
+        assert!(matches!(
+            acquire(&root_directory_path, false),
+            Err(Error::Locked(None))
+        ));
+        let lock = acquire(&root_directory_path, true).unwrap();
+        drop(lock);
+        assert!(!path.exists());
+
+        // lock left by a process that is no longer running
+        fs::write(&path, u32::MAX.to_string()).unwrap();
+        let lock = acquire(&root_directory_path, false).unwrap();
+        drop(lock);
+        assert!(!path.exists());
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 0);
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+    }
+}
@@ -1,23 +1,39 @@
 use std::path::{Path, PathBuf};
 
-use crate::commands::packages::Package;
+use crate::commands::catalogue::Catalogue;
+use crate::commands::config::Config;
+use crate::commands::dependencies::Providers;
+use crate::commands::lock::exit;
+use crate::commands::packages::{InstallReason, Package};
+use crate::commands::repositories::Repository;
+use crate::commands::scripts::Runner;
 use crate::commands::utils::YesNoAnswer::{NO, YES};
-use ansi_term::{ANSIString, Color};
-use core::cmp::Ordering;
+use ansi_term::Color;
 use std::collections::BTreeSet;
+use std::env;
 use std::fs;
-use std::process::exit;
-use std::{env, process};
 
+mod archives;
 mod available_packages;
+mod catalogue;
+mod config;
 mod dependencies;
 mod errors;
+mod format;
+mod groups;
+mod hooks;
 mod installed_packages;
 mod installer;
+mod lock;
 mod packages;
 mod paths;
 mod repositories;
+mod reverse_dependencies;
+mod scripts;
+mod search;
+mod transaction;
 mod utils;
+mod versions;
 
 /// Automatically selects the current directory if ./var/lib/packages/installed exists, otherwise
 /// asks the user.
@@ -48,7 +64,7 @@ pub fn root_directory(no_prompt: bool) -> PathBuf {
                 "{}",
                 Color::Red.paint("Could not find installation root directory. Aborting.")
             );
-            process::exit(1);
+            exit(1);
         }
         prompt_for_directory(None)
     };
@@ -56,6 +72,46 @@ pub fn root_directory(no_prompt: bool) -> PathBuf {
     root_directory_path
 }
 
+/// Takes the lock on the root directory for a command that modifies it, or exits if another
+/// process holds it. The lock is released when the returned value is dropped, or by lock::exit.
+/// An invalid installed packages file is then restored from the backup, unless a transaction
+/// is pending (its rollback restores the file).
+pub fn lock_root_directory(root_directory_path: &Path, break_lock: bool) -> lock::Lock {
+    match lock::acquire(root_directory_path, break_lock) {
+        Ok(lock) => {
+            if !transaction::exists(root_directory_path)
+                && installed_packages::restore_backup(root_directory_path).is_err()
+            {
+                println!(
+                    "{}",
+                    Color::Red.paint("Failed to restore the installed packages file.")
+                );
+            }
+            lock
+        }
+        Err(errors::Error::Locked(Some(pid))) => {
+            println!(
+                "{}",
+                Color::Red.paint(format!("The root directory is locked by PID {}.", pid))
+            );
+            println!("If that process is not a running pmw instance, use --break-lock.");
+            exit(1);
+        }
+        Err(errors::Error::Locked(None)) => {
+            println!(
+                "{}",
+                Color::Red.paint("The root directory is locked by another process.")
+            );
+            println!("If that process is not a running pmw instance, use --break-lock.");
+            exit(1);
+        }
+        Err(_) => {
+            println!("{}", Color::Red.paint("Failed to lock the root directory."));
+            exit(1);
+        }
+    }
+}
+
 pub fn list_installed_packages(root_directory_path: &Path, packages: BTreeSet<&str>) {
     let path = paths::get_installed_packages_file_path(root_directory_path);
     if path.exists() {
@@ -103,73 +159,375 @@ pub fn list_all_installed_packages(root_directory_path: &Path) {
     }
 }
 
-pub fn search_available_packages(root_directory_path: &Path, terms: BTreeSet<&str>) {
+/// Lists the installed packages that have a newer version available,
+/// and exits with status 100 if there are any (1 is left for errors).
+pub fn list_outdated_packages(root_directory_path: &Path) {
+    let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
+    if !installed_packages_file_path.exists() {
+        println!("No package installed.");
+        return;
+    }
+    let installed_packages = installed_packages::get_catalogue(&installed_packages_file_path);
+    let available_packages_file_path = paths::get_available_packages_file_path(root_directory_path);
+    let available_packages = available_packages::get_catalogue(&available_packages_file_path);
+    let upgrades = available_packages::upgrades(&installed_packages, &available_packages);
+    if upgrades.is_empty() {
+        println!("All packages are up to date.");
+        return;
+    }
+    upgrades.iter().for_each(|(installed, available)| {
+        println!(
+            "{} {} \u{2192} {} {}",
+            Color::Purple.paint(installed.name()),
+            &installed.version,
+            Color::Green.paint(&available.version),
+            available.repository.name()
+        )
+    });
+    exit(100);
+}
+
+/// Lists the available groups with their number of members,
+/// or the members of the specified groups.
+pub fn list_groups(
+    root_directory_path: &Path,
+    group_names: BTreeSet<&str>,
+    environment: Option<&str>,
+) {
+    let environment = get_environment(environment);
     let path = paths::get_available_packages_file_path(root_directory_path);
-    let packages = available_packages::get_packages(&path);
-    let mut results: Vec<_> = packages
-        .iter()
-        .filter_map(|package| {
-            let name = package.name();
-            let score: u16 = terms
-                .iter()
-                .filter_map(|&term| {
-                    if name == term {
-                        Some(8u16)
-                    } else if name.starts_with(term) {
-                        Some(4u16)
-                    } else if name.starts_with(format!("lib{}", term).as_str()) {
-                        Some(2u16)
-                    } else if name.contains(term) {
-                        Some(1u16)
-                    } else {
-                        None
-                    }
-                })
-                .sum();
-            if score == 0 {
-                None
-            } else {
-                Some((score, package))
+    let available_packages = available_packages::get_catalogue(&path);
+    if group_names.is_empty() {
+        for group in available_packages.groups() {
+            println!(
+                "{} ({})",
+                Color::Purple.paint(group),
+                available_packages.group(group).len()
+            );
+        }
+        return;
+    }
+    for name in group_names {
+        match groups::find(name, environment, &available_packages) {
+            Some(group) => {
+                for member in available_packages.group(&group) {
+                    println!(
+                        "{} {} {}",
+                        Color::Purple.paint(&group),
+                        member.name(),
+                        Color::Green.paint(&member.version)
+                    );
+                }
+            }
+            None => println!(
+                "{}",
+                Color::Red.paint(format!("Could not find group: {}", name))
+            ),
+        }
+    }
+}
+
+/// Options for the search command.
+pub struct SearchOptions<'a> {
+    /// Treat all terms as regular expressions.
+    pub regex: bool,
+    /// Only show packages from this repository.
+    pub repository: Option<&'a str>,
+    /// Only show installed (true) or not installed (false) packages.
+    pub installed: Option<bool>,
+}
+
+pub fn search_available_packages(
+    root_directory_path: &Path,
+    terms: BTreeSet<&str>,
+    options: SearchOptions,
+) {
+    let repository = options.repository.map(|name| match Repository::from(name) {
+        Some(repository) => repository,
+        None => {
+            println!(
+                "{}",
+                Color::Red.paint(format!("Unknown repository: {}", name))
+            );
+            exit(1);
+        }
+    });
+    let terms: Vec<_> = terms
+        .into_iter()
+        .map(|term| match search::Term::parse(term, options.regex) {
+            Ok(term) => term,
+            Err(_) => {
+                println!(
+                    "{}",
+                    Color::Red.paint(format!("Invalid search pattern: {}", term))
+                );
+                exit(1);
             }
         })
         .collect();
-    results.sort_by(|a, b| match &a.0.cmp(&b.0) {
-        Ordering::Equal => a.1.cmp(b.1).reverse(),
-        &it => it.reverse(),
+    let path = paths::get_available_packages_file_path(root_directory_path);
+    let packages = available_packages::get_catalogue(&path);
+    let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
+    let installed_packages = installed_packages::get_catalogue(&installed_packages_file_path);
+    let installed_version = |package: &Package| {
+        installed_packages
+            .named(package.name())
+            .map(|it| it.version.as_str())
+    };
+    let candidates = packages.iter().filter(|&package| {
+        repository
+            .map(|it| package.repository == it)
+            .unwrap_or(true)
+            && options
+                .installed
+                .map(|it| installed_version(package).is_some() == it)
+                .unwrap_or(true)
     });
-    results.iter().for_each(|it| {
-        let package = it.1;
-        let mut name = ANSIString::from(package.name());
-        let version = &package.version;
-        for &term in &terms {
-            let replacement = Color::Green.paint(term);
-            let pieces: Vec<_> = name.split(term).collect();
-            let mut iter = pieces.iter();
-            let mut str = iter.next().map(|it| it.clone()).unwrap_or("").to_string();
-            while let Some(&cur) = iter.next() {
-                str = format!("{}{}{}", str, replacement, cur)
-            }
-            name = ANSIString::from(str);
+    // group the results by short name (e.g. gcc for mingw-w64-x86_64-gcc and msys gcc)
+    let mut groups: Vec<(&str, Vec<&Package>)> = Vec::new();
+    for (_, package) in search::search(candidates, &terms) {
+        match groups.iter_mut().find(|it| it.0 == package.short_name()) {
+            Some(group) => group.1.push(package),
+            None => groups.push((package.short_name(), vec![package])),
+        }
+    }
+    groups.iter().for_each(|(short_name, packages)| {
+        let mut name = short_name.to_string();
+        for term in &terms {
+            name = term.highlight(&name);
+        }
+        let entries: Vec<_> = packages
+            .iter()
+            .map(|&package| {
+                let marker = match installed_version(package) {
+                    Some(version) if version == package.version => {
+                        format!(" {}", Color::Cyan.paint("[installed]"))
+                    }
+                    Some(version) => format!(
+                        " {}",
+                        Color::Cyan.paint(format!("[installed: {}]", version))
+                    ),
+                    None => "".to_string(),
+                };
+                format!(
+                    "{} {}{}",
+                    Color::Purple.paint(package.repository.name()),
+                    &package.version,
+                    marker
+                )
+            })
+            .collect();
+        println!("{} {}", &name, entries.join(", "));
+        if let Some(description) = packages
+            .iter()
+            .find_map(|it| it.metadata.description.as_ref())
+        {
+            println!("    {}", description);
         }
-        // println!("{}", Color::Purple.paint(format!("{} {}", &name, version)));
-        println!("{} {}", &name, version);
     });
 }
 
+pub fn show_package_info(
+    root_directory_path: &Path,
+    package_names: BTreeSet<&str>,
+    environment: Option<&str>,
+) {
+    let environment = get_environment(environment);
+    let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
+    let installed_packages = installed_packages::get_packages(&installed_packages_file_path);
+    let available_packages_file_path = paths::get_available_packages_file_path(root_directory_path);
+    let available_packages = available_packages::get_catalogue(&available_packages_file_path);
+    let mut not_found = Vec::new();
+    for name in package_names {
+        let available = available_packages::find(name, environment, &available_packages);
+        let installed = installed_packages.iter().find(|&it| {
+            it.matches(name)
+                || available
+                    .map(|available| it.name() == available.name())
+                    .unwrap_or(false)
+        });
+        match (installed, available) {
+            (Some(installed), available) => {
+                // packages installed before metadata was recorded only have the basic fields
+                let metadata_source = if installed.metadata.description.is_some() {
+                    installed
+                } else {
+                    available_packages.get(installed).unwrap_or(installed)
+                };
+                print_package_info(installed, metadata_source, available);
+            }
+            (None, Some(available)) => print_package_info(available, available, None),
+            (None, None) => not_found.push(name),
+        }
+    }
+    if !not_found.is_empty() {
+        println!(
+            "{}",
+            Color::Red.paint(format!("Could not find package: {}", not_found.join(", ")))
+        );
+        exit(1);
+    }
+}
+
+fn print_package_info(package: &Package, metadata_source: &Package, latest: Option<&Package>) {
+    let none = "None".to_string();
+    let metadata = &metadata_source.metadata;
+    let list = |values: &Vec<String>| {
+        if values.is_empty() {
+            none.clone()
+        } else {
+            values.join("  ")
+        }
+    };
+    let mut lines = vec![
+        ("Repository", package.repository.name().to_string()),
+        ("Name", package.name().to_string()),
+        ("Version", package.version.clone()),
+    ];
+    if let Some(latest) = latest {
+        if latest.version != package.version {
+            lines.push(("Available Version", latest.version.clone()));
+        }
+    }
+    lines.append(&mut vec![
+        (
+            "Description",
+            metadata.description.clone().unwrap_or_else(|| none.clone()),
+        ),
+        (
+            "URL",
+            metadata.homepage.clone().unwrap_or_else(|| none.clone()),
+        ),
+        ("Licenses", list(&metadata.licenses)),
+        ("Groups", list(&metadata.groups)),
+        (
+            "Provides",
+            list(&package.names.iter().skip(1).cloned().collect()),
+        ),
+        (
+            "Depends On",
+            list(package.dependencies.as_ref().unwrap_or(&vec![])),
+        ),
+        (
+            "Optional Deps",
+            if package.optional_dependencies.is_empty() {
+                none.clone()
+            } else {
+                package
+                    .optional_dependencies
+                    .iter()
+                    .map(|it| match dependencies::optional_dependency(it) {
+                        (dependency, Some(reason)) => {
+                            format!("{} \u{2014} {}", dependency, reason)
+                        }
+                        (dependency, None) => dependency.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(&format!("\n{:20}", ""))
+            },
+        ),
+        (
+            "Download Size",
+            metadata
+                .compressed_size
+                .map(utils::format_size)
+                .unwrap_or_else(|| none.clone()),
+        ),
+        (
+            "Installed Size",
+            metadata
+                .installed_size
+                .map(utils::format_size)
+                .unwrap_or_else(|| none.clone()),
+        ),
+        (
+            "Packager",
+            metadata.packager.clone().unwrap_or_else(|| none.clone()),
+        ),
+        (
+            "Build Date",
+            metadata
+                .build_date
+                .map(utils::format_date)
+                .unwrap_or_else(|| none.clone()),
+        ),
+        (
+            "Base",
+            metadata.base.clone().unwrap_or_else(|| none.clone()),
+        ),
+    ]);
+    if let Some(ref installation) = package.installation {
+        lines.push(("Install Date", utils::format_date(installation.date)));
+        lines.push((
+            "Install Reason",
+            match installation.reason {
+                InstallReason::Explicit => "Explicitly installed".to_string(),
+                InstallReason::Dependency => "Installed as a dependency".to_string(),
+            },
+        ));
+    }
+    for (label, value) in lines {
+        println!(
+            "{}: {}",
+            Color::Purple.paint(format!("{:<18}", label)),
+            value
+        );
+    }
+    println!();
+}
+
+/// Lists the dependencies of the packages, as a flat list, as a tree (tree=true),
+/// or as a Graphviz graph (dot=true).
 pub fn list_dependencies(
     root_directory_path: &Path,
     package_names: BTreeSet<&str>,
+    environment: Option<&str>,
     no_prompt: bool,
+    tree: bool,
+    dot: bool,
 ) {
     let available_packages_file_path = paths::get_available_packages_file_path(root_directory_path);
-    let available_packages = available_packages::get_packages(&available_packages_file_path);
-    let empty = BTreeSet::new();
+    let available_packages = available_packages::get_catalogue(&available_packages_file_path);
+    let packages = get_packages(root_directory_path, package_names, environment, no_prompt);
+    let providers = Providers::new(&config::read(root_directory_path), no_prompt);
+    if tree || dot {
+        let installed_packages_file_path =
+            paths::get_installed_packages_file_path(root_directory_path);
+        let installed_packages = installed_packages::get_catalogue(&installed_packages_file_path);
+        if tree {
+            for node in dependencies::tree(
+                packages.iter().collect(),
+                &installed_packages,
+                &available_packages,
+                &providers,
+            ) {
+                print_tree_node(&node, "", "");
+            }
+        } else {
+            let results = dependencies::list(
+                packages.iter().collect(),
+                &installed_packages,
+                &available_packages,
+                &providers,
+            );
+            println!(
+                "{}",
+                dependencies::dot(
+                    &results,
+                    &installed_packages,
+                    &available_packages,
+                    &providers
+                )
+            );
+        }
+        return;
+    }
+    let empty = Catalogue::from(BTreeSet::new());
     let mut results = dependencies::list(
-        get_packages(root_directory_path, package_names, no_prompt)
-            .iter()
-            .collect(),
+        packages.iter().collect(),
         &empty,
         &available_packages,
+        &providers,
     );
     results.sort();
     results.iter().for_each(|package| {
@@ -181,23 +539,586 @@ pub fn list_dependencies(
     });
 }
 
+fn print_tree_node(node: &dependencies::TreeNode, prefix: &str, children_prefix: &str) {
+    let name = match node.package {
+        Some(package) => format!(
+            "{} {}",
+            Color::Purple.paint(package.name()),
+            &package.version
+        ),
+        None => Color::Red.paint(node.name).to_string(),
+    };
+    let marker = match node.state {
+        dependencies::NodeState::New => "".to_string(),
+        dependencies::NodeState::Installed => Color::Cyan.paint(" [installed]").to_string(),
+        dependencies::NodeState::Cycle => Color::Yellow.paint(" (cycle)").to_string(),
+        dependencies::NodeState::Repeated => " (*)".to_string(),
+        dependencies::NodeState::Missing => Color::Red.paint(" (not found)").to_string(),
+    };
+    println!("{}{}{}", prefix, name, marker);
+    let count = node.children.len();
+    for (i, child) in node.children.iter().enumerate() {
+        if i + 1 == count {
+            print_tree_node(
+                child,
+                &format!("{}\u{2514}\u{2500}\u{2500} ", children_prefix),
+                &format!("{}    ", children_prefix),
+            );
+        } else {
+            print_tree_node(
+                child,
+                &format!("{}\u{251c}\u{2500}\u{2500} ", children_prefix),
+                &format!("{}\u{2502}   ", children_prefix),
+            );
+        }
+    }
+}
+
+/// Lists the installed and available packages that depend on the packages,
+/// directly or, if recursive is true, indirectly.
+pub fn list_reverse_dependencies(
+    root_directory_path: &Path,
+    package_names: BTreeSet<&str>,
+    environment: Option<&str>,
+    recursive: bool,
+) {
+    let environment = get_environment(environment);
+    let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
+    let installed_packages = installed_packages::get_catalogue(&installed_packages_file_path);
+    let available_packages_file_path = paths::get_available_packages_file_path(root_directory_path);
+    let available_packages = available_packages::get_catalogue(&available_packages_file_path);
+    let installed_index = reverse_dependencies::ReverseDependencies::new(&installed_packages);
+    let available_index = reverse_dependencies::ReverseDependencies::new(&available_packages);
+    for name in package_names {
+        let available = available_packages::find(name, environment, &available_packages);
+        let installed = installed_packages.iter().find(|&it| {
+            it.matches(name)
+                || available
+                    .map(|available| it.name() == available.name())
+                    .unwrap_or(false)
+        });
+        if installed.is_none() && available.is_none() {
+            println!("{} not found.", Color::Purple.paint(name));
+            continue;
+        }
+        if let Some(installed) = installed {
+            println!(
+                "Installed packages depending on {}:",
+                Color::Purple.paint(installed.name())
+            );
+            print_dependents(&installed_index, installed, recursive);
+        }
+        if let Some(available) = available {
+            println!(
+                "Available packages depending on {}:",
+                Color::Purple.paint(available.name())
+            );
+            print_dependents(&available_index, available, recursive);
+        }
+    }
+}
+
+fn print_dependents<'a>(
+    index: &reverse_dependencies::ReverseDependencies<'a>,
+    package: &'a Package,
+    recursive: bool,
+) {
+    let mut packages = if recursive {
+        index.all_dependents(package)
+    } else {
+        index.dependents(package).to_vec()
+    };
+    packages.sort();
+    if packages.is_empty() {
+        println!("  none");
+    }
+    for package in packages {
+        println!(
+            "  {} {} {}",
+            Color::Purple.paint(package.name()),
+            &package.version,
+            package.repository.name()
+        );
+    }
+}
+
+/// Installs the packages and their missing dependencies, and, if with_optional is true,
+/// their optional dependencies.
+/// Packages that are already installed and up to date are skipped, unless reinstall is true.
 pub fn install_packages(
     root_directory_path: &Path,
     package_names: BTreeSet<&str>,
+    environment: Option<&str>,
     no_prompt: bool,
+    dry_run: bool,
+    with_optional: bool,
+    reinstall: bool,
 ) {
-    installer::check_for_pending_installation(root_directory_path, no_prompt);
+    installer::check_for_pending_installation(root_directory_path, no_prompt, dry_run);
+    let mut packages = get_packages(root_directory_path, package_names, environment, no_prompt);
+    let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
+    let installed_packages = installed_packages::get_catalogue(&installed_packages_file_path);
+    if !reinstall {
+        packages.retain(|package| match installed_packages.named(package.name()) {
+            Some(installed) if installed.version == package.version => {
+                println!(
+                    "{} {} is up to date, skipping.",
+                    Color::Purple.paint(package.name()),
+                    &package.version
+                );
+                // the package was installed as a dependency and is now requested by the user
+                if !dry_run
+                    && installed.installation.as_ref().map(|it| it.reason)
+                        == Some(InstallReason::Dependency)
+                {
+                    if installed_packages::set_reason(
+                        root_directory_path,
+                        package.name(),
+                        InstallReason::Explicit,
+                    )
+                    .is_err()
+                    {
+                        println!(
+                            "{}",
+                            Color::Red.paint(format!("Failed to update {}.", package.name()))
+                        );
+                        exit(1);
+                    }
+                    println!(
+                        "{} marked as {}.",
+                        Color::Purple.paint(package.name()),
+                        InstallReason::Explicit.name()
+                    );
+                }
+                false
+            }
+            _ => true,
+        });
+    }
+    let providers = Providers::new(&config::read(root_directory_path), no_prompt);
+    let plan = installer::plan(root_directory_path, packages, with_optional, &providers);
+    if plan.is_empty() {
+        println!("Nothing to install.");
+        return;
+    }
+    if !confirm_transaction(&plan.packages(), &installed_packages, no_prompt, dry_run) {
+        exit(0);
+    }
+    // the confirmed plan is installed as is
     installer::install(
         root_directory_path,
-        get_packages(root_directory_path, package_names, no_prompt),
+        &plan,
+        &Runner::new(root_directory_path),
     );
+    print_optional_dependencies(root_directory_path, &plan.packages());
+}
+
+/// Shows the optional dependencies of the packages that are not installed.
+fn print_optional_dependencies(root_directory_path: &Path, packages: &[Package]) {
+    let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
+    let installed_packages = installed_packages::get_catalogue(&installed_packages_file_path);
+    for package in packages {
+        let missing: Vec<_> = package
+            .optional_dependencies
+            .iter()
+            .map(|it| dependencies::optional_dependency(it))
+            .filter(|(dependency, _)| {
+                dependencies::dependency_name(dependency)
+                    .and_then(|name| installed_packages.latest_version(name))
+                    .is_none()
+            })
+            .collect();
+        if missing.is_empty() {
+            continue;
+        }
+        println!("{}", Color::Purple.paint(package.name()));
+        for (dependency, reason) in missing {
+            match reason {
+                Some(reason) => println!("  optional: {} \u{2014} {}", dependency, reason),
+                None => println!("  optional: {}", dependency),
+            }
+        }
+    }
 }
 
-pub fn update_packages(root_directory_path: &Path, package_names: BTreeSet<&str>, no_prompt: bool) {
+/// Extracts the installed version of the packages again.
+/// Their entry in the installed packages file (and so their install reason) is kept.
+pub fn reinstall_packages(
+    root_directory_path: &Path,
+    package_names: BTreeSet<&str>,
+    environment: Option<&str>,
+    no_prompt: bool,
+    dry_run: bool,
+) {
+    installer::check_for_pending_installation(root_directory_path, no_prompt, dry_run);
+    let environment = get_environment(environment);
+    let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
+    let installed_packages = installed_packages::get_catalogue(&installed_packages_file_path);
+    let available_packages_file_path = paths::get_available_packages_file_path(root_directory_path);
+    let available_packages = available_packages::get_catalogue(&available_packages_file_path);
+    let mut packages = Vec::new();
+    for name in package_names {
+        let installed = installed_packages.named(name).or_else(|| {
+            available_packages::find(name, environment, &available_packages)
+                .and_then(|available| installed_packages.named(available.name()))
+        });
+        match installed {
+            Some(installed) => packages.push(installed.clone()),
+            None => println!("{} is not installed.", Color::Purple.paint(name)),
+        }
+    }
+    if packages.is_empty() {
+        println!("Nothing to reinstall.");
+        return;
+    }
+    if !confirm_transaction(&packages, &installed_packages, no_prompt, dry_run) {
+        exit(0);
+    }
     installer::update(
         root_directory_path,
-        get_packages(root_directory_path, package_names, no_prompt),
+        packages,
+        false,
+        &Runner::new(root_directory_path),
+    );
+}
+
+/// Updates the packages to their latest version, or every installed package if none is named.
+/// A specific version (name=version, name<version or an archive file) has to be installed
+/// with install instead.
+pub fn update_packages(
+    root_directory_path: &Path,
+    package_names: BTreeSet<&str>,
+    environment: Option<&str>,
+    no_prompt: bool,
+    dry_run: bool,
+) {
+    let versioned: Vec<&str> = package_names
+        .iter()
+        .copied()
+        .filter(|&it| archives::is_archive_path(it) || archives::requirement(it).1.is_some())
+        .collect();
+    if !versioned.is_empty() {
+        println!(
+            "{}",
+            Color::Red.paint(format!(
+                "update only installs the latest version: use install for {}.",
+                versioned.join(", ")
+            ))
+        );
+        exit(1);
+    }
+    installer::check_for_pending_installation(root_directory_path, no_prompt, dry_run);
+    let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
+    let installed_packages = installed_packages::get_catalogue(&installed_packages_file_path);
+    let available_packages_file_path = paths::get_available_packages_file_path(root_directory_path);
+    let available_packages = available_packages::get_catalogue(&available_packages_file_path);
+    let config = config::read(root_directory_path);
+    // without arguments, every installed package with a newer version is upgraded
+    let upgrades: Vec<(&Package, &Package)> = if package_names.is_empty() {
+        available_packages::upgrades(&installed_packages, &available_packages)
+    } else {
+        get_packages(root_directory_path, package_names, environment, no_prompt)
+            .iter()
+            .filter_map(|package| match installed_packages.named(package.name()) {
+                Some(installed) => {
+                    let upgrade = available_packages::upgrade(installed, &available_packages);
+                    if upgrade.is_none() {
+                        println!("{} is up to date.", Color::Purple.paint(package.name()));
+                    }
+                    upgrade.map(|available| (installed, available))
+                }
+                None => {
+                    println!("{} is not installed.", Color::Purple.paint(package.name()));
+                    None
+                }
+            })
+            .collect()
+    };
+    // the upgraded packages can have new dependencies
+    let providers = Providers::new(&config, no_prompt);
+    let upgrades: Vec<&Package> = upgrades
+        .into_iter()
+        .filter(|&(installed, available)| {
+            if config.holds.contains(installed.name()) {
+                println!(
+                    "{} is held, skipping {} \u{2192} {} (see unhold).",
+                    Color::Purple.paint(installed.name()),
+                    &installed.version,
+                    &available.version
+                );
+                return false;
+            }
+            match blocking_hold(
+                available,
+                &installed_packages,
+                &available_packages,
+                &providers,
+                &config,
+            ) {
+                Some(required) => {
+                    println!(
+                        "{}",
+                        Color::Red.paint(format!(
+                            "{} {} can't be updated: it requires {} {}, but {} is held.",
+                            available.name(),
+                            &available.version,
+                            required.name(),
+                            &required.version,
+                            required.name()
+                        ))
+                    );
+                    false
+                }
+                None => true,
+            }
+        })
+        .map(|(_, available)| available)
+        .collect();
+    if upgrades.is_empty() {
+        println!("Nothing to update.");
+        return;
+    }
+    let packages: Vec<Package> = dependencies::list(
+        upgrades,
+        &installed_packages,
+        &available_packages,
+        &providers,
     )
+    .into_iter()
+    .filter(|package| match installed_packages.named(package.name()) {
+        Some(installed) => {
+            versions::compare(&package.version, &installed.version) == std::cmp::Ordering::Greater
+        }
+        None => true,
+    })
+    .collect();
+    if !confirm_transaction(&packages, &installed_packages, no_prompt, dry_run) {
+        exit(0);
+    }
+    installer::update(
+        root_directory_path,
+        packages,
+        false,
+        &Runner::new(root_directory_path),
+    )
+}
+
+/// Returns the first package (direct or transitive dependency) that updating the package would
+/// also change, although its installed version is held (e.g. gcc-libs 12.1.0 when gcc-libs
+/// 11.2.0 is held).
+fn blocking_hold(
+    package: &Package,
+    installed_packages: &Catalogue,
+    available_packages: &Catalogue,
+    providers: &Providers,
+    config: &Config,
+) -> Option<Package> {
+    dependencies::list(
+        vec![package],
+        installed_packages,
+        available_packages,
+        providers,
+    )
+    .into_iter()
+    .find(|it| {
+        config.holds.contains(it.name())
+            && installed_packages
+                .named(it.name())
+                .map(|installed| installed.version != it.version)
+                .unwrap_or(false)
+    })
+}
+
+/// Holds installed packages (hold=true) so that update skips them, or removes the hold.
+pub fn hold_packages(root_directory_path: &Path, package_names: BTreeSet<&str>, hold: bool) {
+    let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
+    let installed_packages = installed_packages::get_catalogue(&installed_packages_file_path);
+    for name in package_names {
+        // the hold can be removed from packages that are no longer installed
+        let name = match available_packages::find(name, None, &installed_packages) {
+            Some(package) => package.name(),
+            None if !hold => name,
+            None => {
+                println!("{} is not installed.", Color::Purple.paint(name));
+                continue;
+            }
+        };
+        match config::set_hold(root_directory_path, name, hold) {
+            Ok(true) if hold => println!("{} is held.", Color::Purple.paint(name)),
+            Ok(true) => println!("{} is no longer held.", Color::Purple.paint(name)),
+            Ok(false) if hold => println!("{} is already held.", Color::Purple.paint(name)),
+            Ok(false) => println!("{} is not held.", Color::Purple.paint(name)),
+            Err(_) => {
+                println!(
+                    "{}",
+                    Color::Red.paint("Failed to update the configuration file.")
+                );
+                exit(1);
+            }
+        }
+    }
+}
+
+/// Marks installed packages as explicitly installed (explicit=true) or as dependencies.
+pub fn mark_packages(root_directory_path: &Path, package_names: BTreeSet<&str>, explicit: bool) {
+    let reason = if explicit {
+        InstallReason::Explicit
+    } else {
+        InstallReason::Dependency
+    };
+    let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
+    let installed_packages = installed_packages::get_packages(&installed_packages_file_path);
+    for name in package_names {
+        match installed_packages.iter().find(|&it| it.matches(name)) {
+            Some(package) => {
+                if installed_packages::set_reason(root_directory_path, package.name(), reason)
+                    .is_err()
+                {
+                    println!(
+                        "{}",
+                        Color::Red.paint(format!("Failed to update {}.", package.name()))
+                    );
+                    exit(1);
+                }
+                println!(
+                    "{} marked as {}.",
+                    Color::Purple.paint(package.name()),
+                    reason.name()
+                );
+            }
+            None => println!("{} is not installed.", Color::Purple.paint(name)),
+        }
+    }
+}
+
+/// Shows the shortest chain of dependents leading from the installed packages to each explicitly
+/// installed package (e.g. zlib ← libxml2 ← python (explicit)).
+pub fn explain_packages(root_directory_path: &Path, package_names: BTreeSet<&str>) {
+    let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
+    let installed_packages = installed_packages::get_catalogue(&installed_packages_file_path);
+    let index = reverse_dependencies::ReverseDependencies::new(&installed_packages);
+    for name in package_names {
+        match installed_packages.latest_version(name) {
+            Some(package) => {
+                let chains = index.why(package);
+                if chains.is_empty() {
+                    println!(
+                        "{} is not required by any explicitly installed package.",
+                        Color::Purple.paint(package.name())
+                    );
+                }
+                for chain in chains {
+                    let names: Vec<_> = chain
+                        .iter()
+                        .map(|it| Color::Purple.paint(it.name()).to_string())
+                        .collect();
+                    println!("{} (explicit)", names.join(" \u{2190} "));
+                }
+            }
+            None => println!("{} is not installed.", Color::Purple.paint(name)),
+        }
+    }
+}
+
+/// Removes the packages installed as dependencies that are not required any more.
+pub fn remove_orphan_packages(root_directory_path: &Path, no_prompt: bool) {
+    installer::check_for_pending_installation(root_directory_path, no_prompt, false);
+    let installed_packages_file_path = paths::get_installed_packages_file_path(root_directory_path);
+    let installed_packages = installed_packages::get_catalogue(&installed_packages_file_path);
+    let orphans = dependencies::orphans(&installed_packages);
+    if orphans.is_empty() {
+        println!("No package to remove.");
+        return;
+    }
+    println!("Packages to remove ({}):", orphans.len());
+    for package in &orphans {
+        println!(
+            "  {} {}",
+            Color::Purple.paint(package.name()),
+            &package.version
+        );
+    }
+    let installed_size: u64 = orphans
+        .iter()
+        .filter_map(|it| it.metadata.installed_size)
+        .sum();
+    println!();
+    println!("Total removed size: {}", utils::format_size(installed_size));
+    if utils::yes_or_no("Proceed with the removal?", YES, no_prompt, None) == NO {
+        exit(0);
+    }
+    installer::remove(
+        root_directory_path,
+        orphans.into_iter().cloned().collect(),
+        &Runner::new(root_directory_path),
+    );
+}
+
+/// Shows the packages about to be installed or upgraded, in installation order,
+/// with the total download and installed sizes, and asks for confirmation.
+/// Returns false if the user declined or if this is a dry run.
+fn confirm_transaction(
+    packages: &[Package],
+    installed_packages: &Catalogue,
+    no_prompt: bool,
+    dry_run: bool,
+) -> bool {
+    println!("Packages ({}):", packages.len());
+    for package in packages {
+        match installed_packages.named(package.name()) {
+            Some(installed) if installed.version == package.version => println!(
+                "  {} {} {}",
+                Color::Purple.paint(package.name()),
+                &package.version,
+                Color::Cyan.paint("(reinstall)")
+            ),
+            Some(installed) => println!(
+                "  {} {} -> {} {}",
+                Color::Purple.paint(package.name()),
+                &installed.version,
+                &package.version,
+                Color::Cyan.paint(
+                    if versions::compare(&package.version, &installed.version).is_lt() {
+                        "(downgrade)"
+                    } else {
+                        "(upgrade)"
+                    }
+                )
+            ),
+            None => println!(
+                "  {} {} {}",
+                Color::Purple.paint(package.name()),
+                &package.version,
+                Color::Green.paint("(new)")
+            ),
+        }
+    }
+    let download_size: u64 = packages
+        .iter()
+        .filter_map(|it| it.metadata.compressed_size)
+        .sum();
+    let installed_size: u64 = packages
+        .iter()
+        .filter_map(|it| it.metadata.installed_size)
+        .sum();
+    println!();
+    println!(
+        "Total download size:  {}",
+        utils::format_size(download_size)
+    );
+    println!(
+        "Total installed size: {}",
+        utils::format_size(installed_size)
+    );
+    if dry_run {
+        println!("Dry run, no changes were made.");
+        return false;
+    }
+    utils::yes_or_no("Proceed with the installation?", YES, no_prompt, None) == YES
+}
+
+pub fn recover(root_directory_path: &Path) {
+    installer::recover(root_directory_path);
 }
 
 fn prompt_for_directory(default: Option<&Path>) -> PathBuf {
@@ -225,21 +1146,87 @@ fn prompt_for_directory(default: Option<&Path>) -> PathBuf {
     }
 }
 
+/// The names of the environments that can be selected with --env.
+pub fn environment_names() -> Vec<&'static str> {
+    Repository::environments().map(|it| it.name()).collect()
+}
+
+/// Selects the environment whose repository is synced along with msys.
+/// Without --env, the default environment (mingw64) is synced.
+pub fn select_environment(name: Option<&str>) {
+    if let Some(environment) = get_environment(name) {
+        Repository::select_environment(environment);
+    }
+}
+
+fn get_environment(name: Option<&str>) -> Option<&'static Repository> {
+    name.map(|name| match Repository::environment(name) {
+        Some(environment) => environment,
+        None => {
+            println!(
+                "{}",
+                Color::Red.paint(format!("Unknown environment: {}", name))
+            );
+            exit(1);
+        }
+    })
+}
+
+/// Finds the packages to install from the names typed by the user.
+/// A name can be followed by a version requirement (gcc=11.2.0 or gcc<12),
+/// or be replaced by the path of a package archive file or by the name of a group.
 fn get_packages(
     root_directory_path: &Path,
     package_names: BTreeSet<&str>,
+    environment: Option<&str>,
     no_prompt: bool,
 ) -> BTreeSet<Package> {
+    let environment = get_environment(environment);
     let path = paths::get_available_packages_file_path(root_directory_path);
-    let available_packages = available_packages::get_packages(&path);
+    let available_packages = available_packages::get_catalogue(&path);
     let mut not_found: Vec<&str> = Vec::new();
     let mut packages: BTreeSet<Package> = BTreeSet::new();
-    for name in package_names {
-        match available_packages::latest_version(name, &available_packages) {
+    let config = config::read(root_directory_path);
+    for argument in package_names {
+        if archives::is_archive_path(argument) {
+            match archives::read_archive_file(root_directory_path, Path::new(argument)) {
+                Ok(package) => {
+                    packages.insert(package);
+                }
+                Err(_) => not_found.push(argument),
+            }
+            continue;
+        }
+        // a group is expanded to the selected members
+        if let (name, None) = archives::requirement(argument) {
+            if available_packages::find(name, environment, &available_packages).is_none() {
+                if let Some(group) = groups::find(name, environment, &available_packages) {
+                    let members = available_packages.group(&group);
+                    for member in groups::select(&group, &members, no_prompt) {
+                        packages.insert(member.clone());
+                    }
+                    continue;
+                }
+            }
+        }
+        let package = match archives::requirement(argument) {
+            (name, None) => {
+                available_packages::find(name, environment, &available_packages).cloned()
+            }
+            (name, Some(requirement)) => archives::find(
+                root_directory_path,
+                name,
+                &requirement,
+                environment,
+                &available_packages,
+                &config,
+            ),
+        };
+        match package {
             Some(package) => {
-                packages.insert(package.clone());
+                packages.insert(package);
             }
-            None => not_found.push(name),
+            None => not_found.push(argument),
         }
     }
     match not_found.len() {
@@ -255,7 +1242,7 @@ fn get_packages(
             if packages.is_empty()
                 || utils::yes_or_no("Abort installation?", NO, no_prompt, None) == YES
             {
-                process::exit(1);
+                exit(1);
             }
         }
         n => {
@@ -267,7 +1254,7 @@ fn get_packages(
                 ))
             );
             if packages.len() <= n || utils::yes_or_no("Abort?", NO, no_prompt, None) == YES {
-                process::exit(1);
+                exit(1);
             }
         }
     }
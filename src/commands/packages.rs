@@ -3,6 +3,7 @@ use std::result::Result as StdResult;
 
 use crate::commands::errors::Error::IOError;
 use crate::commands::errors::{Error, Error::ParseError, Result};
+use crate::commands::format;
 use crate::commands::repositories::{Repository, RepositoryVersion};
 use crate::commands::utils::Compression;
 use std::cmp::Ordering;
@@ -13,6 +14,26 @@ use std::path::Path;
 
 const PACKAGE_EXTENSION: &'static str = "pkg.tar";
 
+const REPOSITORY_KEY: &str = "repository";
+const NAME_KEY: &str = "name";
+const PROVIDES_KEY: &str = "provides";
+const VERSION_KEY: &str = "version";
+const COMPRESSION_KEY: &str = "compression";
+const ARCH_KEY: &str = "arch";
+const DEPENDS_KEY: &str = "depends";
+const OPTIONAL_DEPENDS_KEY: &str = "optdepends";
+const DESCRIPTION_KEY: &str = "description";
+const URL_KEY: &str = "url";
+const LICENSES_KEY: &str = "licenses";
+const COMPRESSED_SIZE_KEY: &str = "csize";
+const INSTALLED_SIZE_KEY: &str = "isize";
+const BUILD_DATE_KEY: &str = "builddate";
+const PACKAGER_KEY: &str = "packager";
+const GROUPS_KEY: &str = "groups";
+const BASE_KEY: &str = "base";
+const INSTALL_DATE_KEY: &str = "installdate";
+const INSTALL_REASON_KEY: &str = "reason";
+
 #[derive(Debug, Clone)]
 pub struct Package {
     pub repository: &'static Repository,
@@ -21,12 +42,71 @@ pub struct Package {
     pub compression: Option<&'static Compression>,
     pub arch: Option<String>,
     pub dependencies: Option<Vec<String>>,
+    /// Optional dependencies, with an optional reason (e.g. python: for the plugin interface).
+    pub optional_dependencies: Vec<String>,
+    pub metadata: Metadata,
+    pub installation: Option<Installation>,
+}
+
+/// Informational fields from the repository desc file (%DESC%, %URL%, %LICENSE%, ...).
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    pub description: Option<String>,
+    pub homepage: Option<String>,
+    pub licenses: Vec<String>,
+    pub compressed_size: Option<u64>,
+    pub installed_size: Option<u64>,
+    pub build_date: Option<u64>,
+    pub packager: Option<String>,
+    pub groups: Vec<String>,
+    pub base: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum InstallReason {
+    Explicit,
+    Dependency,
+}
+
+impl InstallReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Explicit => "explicit",
+            Self::Dependency => "dependency",
+        }
+    }
+    pub fn from(name: &str) -> Option<Self> {
+        match name {
+            "explicit" => Some(Self::Explicit),
+            "dependency" => Some(Self::Dependency),
+            _ => None,
+        }
+    }
+}
+
+/// Only set for packages read from the installed packages file.
+#[derive(Debug, Clone)]
+pub struct Installation {
+    /// Seconds since the unix epoch.
+    pub date: u64,
+    pub reason: InstallReason,
 }
 
 impl Package {
     pub fn name(&self) -> &str {
         self.names.first().unwrap()
     }
+    /// The name without the repository package prefix (e.g. gcc for mingw-w64-x86_64-gcc).
+    pub fn short_name(&self) -> &str {
+        self.repository.short_name(self.name())
+    }
+    /// Packages installed without a recorded install reason are considered explicit.
+    pub fn explicit(&self) -> bool {
+        self.installation
+            .as_ref()
+            .map(|it| it.reason == InstallReason::Explicit)
+            .unwrap_or(true)
+    }
     pub fn matches(&self, name: &str) -> bool {
         self.names.iter().any(|it| it == name)
     }
@@ -82,11 +162,83 @@ impl Ord for Package {
     }
 }
 
-/// {repo_name} {package_name} {package_version} {compression_extension}
-/// followed by optional dependencies: + {package_name_with_optional_version_constraints} ...
+/// Version 2 lines are tab separated key=value fields (see format.rs).
+/// Version 1 lines are positional columns starting with the repository name.
 impl TryFrom<&str> for Package {
     type Error = Error;
     fn try_from(value: &str) -> StdResult<Self, Self::Error> {
+        let first = value.split('\t').next().unwrap_or("");
+        if first.contains('=') {
+            Self::parse_fields(value)
+        } else {
+            Self::parse_columns(value)
+        }
+    }
+}
+
+impl Package {
+    /// repository={repo_name} name={package_name} provides={names} version={package_version}
+    /// compression={compression_extension} arch={arch} depends={dependencies} description=...
+    /// Unknown keys are ignored so that fields can be added without breaking older versions.
+    fn parse_fields(value: &str) -> Result<Self> {
+        let fields = format::fields(value);
+        let get = |key: &str| {
+            fields
+                .iter()
+                .find(|(it, _)| *it == key)
+                .map(|(_, value)| *value)
+        };
+        let text = |key: &str| get(key).map(format::unescape).filter(|it| !it.is_empty());
+        let list = |key: &str| get(key).map(format::split_list).unwrap_or_default();
+        let number = |key: &str| -> Result<Option<u64>> {
+            text(key)
+                .map(|it| it.parse::<u64>().map_err(|_| ParseError))
+                .transpose()
+        };
+        let repository =
+            Repository::from(&text(REPOSITORY_KEY).ok_or(ParseError)?).ok_or(ParseError)?;
+        let names = std::iter::once(text(NAME_KEY).ok_or(ParseError)?)
+            .chain(list(PROVIDES_KEY))
+            .collect();
+        let version = text(VERSION_KEY).ok_or(ParseError)?;
+        let compression = match text(COMPRESSION_KEY) {
+            Some(extension) => Some(Compression::from_extension(&extension).ok_or(ParseError)?),
+            None => None,
+        };
+        let metadata = Metadata {
+            description: text(DESCRIPTION_KEY),
+            homepage: text(URL_KEY),
+            licenses: list(LICENSES_KEY),
+            compressed_size: number(COMPRESSED_SIZE_KEY)?,
+            installed_size: number(INSTALLED_SIZE_KEY)?,
+            build_date: number(BUILD_DATE_KEY)?,
+            packager: text(PACKAGER_KEY),
+            groups: list(GROUPS_KEY),
+            base: text(BASE_KEY),
+        };
+        let installation = match (number(INSTALL_DATE_KEY)?, text(INSTALL_REASON_KEY)) {
+            (Some(date), Some(reason)) => Some(Installation {
+                date,
+                reason: InstallReason::from(&reason).ok_or(ParseError)?,
+            }),
+            (None, None) => None,
+            _ => return Err(ParseError),
+        };
+        Ok(Package {
+            repository,
+            names,
+            version,
+            compression,
+            arch: text(ARCH_KEY),
+            dependencies: get(DEPENDS_KEY).map(format::split_list),
+            optional_dependencies: list(OPTIONAL_DEPENDS_KEY),
+            metadata,
+            installation,
+        })
+    }
+    /// {repo_name} {package_name} {package_version} {compression_extension} {arch}
+    /// followed by optional dependencies: + {package_name_with_optional_version_constraints} ...
+    fn parse_columns(value: &str) -> Result<Self> {
         let cols: Vec<&str> = value.split('\t').collect();
         if cols.len() < 3 {
             return Err(ParseError);
@@ -121,6 +273,9 @@ impl TryFrom<&str> for Package {
             compression,
             arch,
             dependencies,
+            optional_dependencies: vec![],
+            metadata: Metadata::default(),
+            installation: None,
         })
     }
 }
@@ -143,21 +298,62 @@ fn url_from(
     )
 }
 
-/// {repo_name} {package_name} {package_version} {compression_extension}
-/// followed by optional dependencies: + {package_name_with_optional_version_constraints} ...
+/// Always uses the latest format version: tab separated key=value fields (see format.rs).
 impl From<&Package> for String {
     fn from(package: &Package) -> Self {
-        let names = package.names.join(", ");
-        let mut cols = vec![package.repository.name(), names.as_str(), &package.version];
-        if let Some(ref compression) = package.compression {
-            cols.push(compression.extension())
+        let metadata = &package.metadata;
+        let mut cols = vec![
+            format::field(REPOSITORY_KEY, package.repository.name()),
+            format::field(NAME_KEY, package.name()),
+        ];
+        if package.names.len() > 1 {
+            cols.push(format::list_field(PROVIDES_KEY, &package.names[1..]));
+        }
+        cols.push(format::field(VERSION_KEY, &package.version));
+        let mut push = |key: &str, value: Option<String>| {
+            if let Some(value) = value {
+                cols.push(format::field(key, &value));
+            }
+        };
+        push(
+            COMPRESSION_KEY,
+            package.compression.map(|it| it.extension().to_string()),
+        );
+        push(ARCH_KEY, package.arch.clone());
+        push(DESCRIPTION_KEY, metadata.description.clone());
+        push(URL_KEY, metadata.homepage.clone());
+        push(
+            COMPRESSED_SIZE_KEY,
+            metadata.compressed_size.map(|it| it.to_string()),
+        );
+        push(
+            INSTALLED_SIZE_KEY,
+            metadata.installed_size.map(|it| it.to_string()),
+        );
+        push(BUILD_DATE_KEY, metadata.build_date.map(|it| it.to_string()));
+        push(PACKAGER_KEY, metadata.packager.clone());
+        push(BASE_KEY, metadata.base.clone());
+        if let Some(ref installation) = package.installation {
+            push(INSTALL_DATE_KEY, Some(installation.date.to_string()));
+            push(
+                INSTALL_REASON_KEY,
+                Some(installation.reason.name().to_string()),
+            );
         }
-        if let Some(ref arch) = package.arch {
-            cols.push(arch);
+        if !metadata.licenses.is_empty() {
+            cols.push(format::list_field(LICENSES_KEY, &metadata.licenses));
+        }
+        if !metadata.groups.is_empty() {
+            cols.push(format::list_field(GROUPS_KEY, &metadata.groups));
         }
         if let Some(ref deps) = package.dependencies {
-            cols.push("+");
-            deps.iter().for_each(|dep| cols.push(dep));
+            cols.push(format::list_field(DEPENDS_KEY, deps));
+        }
+        if !package.optional_dependencies.is_empty() {
+            cols.push(format::list_field(
+                OPTIONAL_DEPENDS_KEY,
+                &package.optional_dependencies,
+            ));
         }
         cols.join("\t")
     }
@@ -303,10 +499,14 @@ mod tests {
             compression,
             arch: Some(arch.to_string()),
             dependencies: Some(vec![dep1, dep2, dep3, dep4, dep5]),
+            optional_dependencies: vec![],
+            metadata: Metadata::default(),
+            installation: None,
         };
         assert_eq!(
             &String::from(&package),
-            "msys\tname\tversion\tzst\tany\t+\tdep1\tdep2=1.0\tdep3\tdep4>0\tdep5"
+            "repository=msys\tname=name\tversion=version\tcompression=zst\tarch=any\t\
+             depends=dep1,dep2=1.0,dep3,dep4>0,dep5"
         )
     }
 
@@ -326,13 +526,48 @@ mod tests {
             compression,
             arch: Some(arch.to_string()),
             dependencies: None,
+            optional_dependencies: vec![],
+            metadata: Metadata::default(),
+            installation: None,
         };
         assert_eq!(
             &String::from(&package),
-            "mingw64\tpackage, package-git\t1.0\txz\tx86_64"
+            "repository=mingw64\tname=package\tprovides=package-git\tversion=1.0\t\
+             compression=xz\tarch=x86_64"
         )
     }
 
+    #[test]
+    fn test_parsing_fields() {
+        let package = Package::try_from(
+            "repository=mingw64\tname=name\tprovides=alias\\,1,other\tversion=1.0-1\t\
+             compression=zst\tarch=any\tdescription=tab\\there\\, and a comma\t\
+             licenses=custom\\,BSD,MIT\tunknown=ignored\tdepends=dep1>=2,dep2\t\
+             optdepends=python: for the plugin interface,perl",
+        )
+        .unwrap();
+        assert_eq!(package.repository, &Repository::Mingw64);
+        assert_eq!(package.names, vec!["name", "alias,1", "other"]);
+        assert_eq!(package.version, "1.0-1");
+        assert_eq!(package.compression, Some(&Compression::ZSTD));
+        assert_eq!(package.arch.as_deref(), Some("any"));
+        assert_eq!(
+            package.metadata.description.as_deref(),
+            Some("tab\there, and a comma")
+        );
+        assert_eq!(package.metadata.licenses, vec!["custom,BSD", "MIT"]);
+        assert_eq!(package.dependencies.unwrap(), vec!["dep1>=2", "dep2"]);
+        assert_eq!(
+            package.optional_dependencies,
+            vec!["python: for the plugin interface", "perl"]
+        );
+        let package = Package::try_from("repository=msys\tname=name\tversion=1\tdepends=").unwrap();
+        assert!(package.dependencies.unwrap().is_empty());
+        assert!(Package::try_from("repository=msys\tname=name").is_err());
+        assert!(Package::try_from("repository=unknown\tname=name\tversion=1").is_err());
+        assert!(Package::try_from("repository=msys\tname=name\tversion=1\tcsize=big").is_err());
+    }
+
     #[test]
     fn test_cant_parse() {
         assert!(Package::try_from("").is_err());
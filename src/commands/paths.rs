@@ -141,8 +141,42 @@ pub fn get_pending_installation_file_path(root_directory_path: &Path) -> PathBuf
     get_directory(root_directory_path).join("pending")
 }
 
+/// List of the files installed by a package.
+pub fn get_package_files_file_path(root_directory_path: &Path, name: &str) -> PathBuf {
+    get_directory(root_directory_path).join("files").join(name)
+}
+
+/// Install script (.INSTALL) of a package.
+pub fn get_package_script_file_path(root_directory_path: &Path, name: &str) -> PathBuf {
+    get_directory(root_directory_path)
+        .join("scripts")
+        .join(name)
+}
+
+pub fn get_config_file_path(root_directory_path: &Path) -> PathBuf {
+    get_directory(root_directory_path).join("config")
+}
+
+/// Downloaded package archives, named like in the repositories.
+pub fn get_archive_cache_directory_path(root_directory_path: &Path) -> PathBuf {
+    get_directory(root_directory_path).join("cache")
+}
+
+pub fn get_lock_file_path(root_directory_path: &Path) -> PathBuf {
+    get_directory(root_directory_path).join("lock")
+}
+
+pub fn get_transaction_directory_path(root_directory_path: &Path) -> PathBuf {
+    get_directory(root_directory_path).join("transaction")
+}
+
 pub fn get_installed_packages_backup_file_path(root_directory_path: &Path) -> PathBuf {
-    get_directory(root_directory_path).join("backup")
+    get_backup_file_path(&get_installed_packages_file_path(root_directory_path))
+}
+
+/// The backup is kept next to the installed packages file.
+pub fn get_backup_file_path(installed_packages_file_path: &Path) -> PathBuf {
+    installed_packages_file_path.with_file_name("backup")
 }
 
 fn get_directory(root_directory_path: &Path) -> PathBuf {
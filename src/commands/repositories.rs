@@ -4,9 +4,10 @@ use std::cmp::Ordering;
 use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
 use std::io::Read;
 use std::path::Path;
+use std::sync::Mutex;
 
 use crate::commands::errors::Result;
-use crate::commands::packages::{Package, Packages};
+use crate::commands::packages::{Metadata, Package, Packages};
 use crate::commands::utils;
 use crate::commands::utils::{Compression, ETag};
 
@@ -31,7 +32,17 @@ pub struct RepositoryVersion {
 }
 
 lazy_static! {
-    static ref ALL: Vec<&'static Repository> = vec![&Repository::Msys, &Repository::Mingw64];
+    static ref ALL: Vec<&'static Repository> = vec![
+        &Repository::Msys,
+        &Repository::Mingw64,
+        &Repository::Clang64,
+        &Repository::Ucrt64
+    ];
+}
+
+lazy_static! {
+    /// The environment whose repository is synced along with msys.
+    static ref SELECTED_ENVIRONMENT: Mutex<&'static Repository> = Mutex::new(&Repository::Mingw64);
 }
 
 impl PartialEq for Repository {
@@ -74,6 +85,38 @@ impl Repository {
             Self::Ucrt64 => "mingw-w64-ucrt-x86_64-",
         }
     }
+    /// Adds the package prefix to the name if it is missing.
+    pub fn qualified_name(&self, name: &str) -> String {
+        if name.starts_with(self.package_prefix()) {
+            name.to_string()
+        } else {
+            format!("{}{}", self.package_prefix(), name)
+        }
+    }
+    /// Removes the package prefix from the name if it is present.
+    pub fn short_name<'a>(&self, name: &'a str) -> &'a str {
+        name.strip_prefix(self.package_prefix()).unwrap_or(name)
+    }
+    /// The environment used for names without a package prefix when they don't match any package.
+    pub fn default_environment() -> &'static Self {
+        &Self::Mingw64
+    }
+    /// The environments (repositories with a package prefix) that can be selected with --env.
+    pub fn environments() -> impl Iterator<Item = &'static Self> {
+        Self::all()
+            .iter()
+            .copied()
+            .filter(|it| !it.package_prefix().is_empty())
+    }
+    pub fn environment(name: &str) -> Option<&'static Self> {
+        Self::environments().find(|it| it.name() == name)
+    }
+    /// Selects the environment whose repository is synced along with msys (mingw64 by default).
+    pub fn select_environment(environment: &'static Self) {
+        *SELECTED_ENVIRONMENT
+            .lock()
+            .unwrap_or_else(|it| it.into_inner()) = environment;
+    }
     pub fn url(&self) -> &'static str {
         match self {
             Self::Msys => "https://repo.msys2.org/msys/x86_64/",
@@ -82,11 +125,23 @@ impl Repository {
             Self::Ucrt64 => "https://repo.msys2.org/ucrt64/x86_64/",
         }
     }
-    pub fn enabled() -> &'static [&'static Self] {
+    /// Every known repository.
+    pub fn all() -> &'static [&'static Self] {
         &ALL
     }
+    /// The repositories that are synced: msys and the selected environment.
+    pub fn enabled() -> &'static [&'static Self] {
+        match *SELECTED_ENVIRONMENT
+            .lock()
+            .unwrap_or_else(|it| it.into_inner())
+        {
+            Self::Clang64 => &[&Self::Msys, &Self::Clang64],
+            Self::Ucrt64 => &[&Self::Msys, &Self::Ucrt64],
+            _ => &[&Self::Msys, &Self::Mingw64],
+        }
+    }
     pub fn from(name: &str) -> Option<&'static Self> {
-        Self::enabled()
+        Self::all()
             .iter()
             .find(|&it| it.name() == name)
             .map(|it| *it)
@@ -154,6 +209,22 @@ impl Repository {
         //name.replace_range(filename.rfind(format!("-{}-", version))?.., "");
         // dependencies are the values of the %DEPENDS% section
         let dependencies = Self::section_values("%DEPENDS%", &sections).or(Some(vec![]));
+        let optional_dependencies =
+            Self::section_values("%OPTDEPENDS%", &sections).unwrap_or_default();
+        let metadata = Metadata {
+            description: Self::section_value("%DESC%", &sections),
+            homepage: Self::section_value("%URL%", &sections),
+            licenses: Self::section_values("%LICENSE%", &sections).unwrap_or_default(),
+            compressed_size: Self::section_value("%CSIZE%", &sections)
+                .and_then(|it| it.parse().ok()),
+            installed_size: Self::section_value("%ISIZE%", &sections)
+                .and_then(|it| it.parse().ok()),
+            build_date: Self::section_value("%BUILDDATE%", &sections)
+                .and_then(|it| it.parse().ok()),
+            packager: Self::section_value("%PACKAGER%", &sections),
+            groups: Self::section_values("%GROUPS%", &sections).unwrap_or_default(),
+            base: Self::section_value("%BASE%", &sections),
+        };
         Some(Package {
             repository: Repository::from(&self.name())?,
             names: std::iter::once(name).chain(names).collect(),
@@ -165,6 +236,9 @@ impl Repository {
             ),
             arch: Some(arch.to_string()),
             dependencies,
+            optional_dependencies,
+            metadata,
+            installation: None,
         })
     }
     /// Searches for the section with the specified section name and returns the value lines
@@ -179,12 +253,93 @@ impl Repository {
         })?;
         Some(section.iter().skip(1).map(|&it| it.to_string()).collect())
     }
+    /// Returns the first value of the section with the specified section name if it is found.
+    fn section_value(key: &str, sections: &Vec<Vec<&str>>) -> Option<String> {
+        Self::section_values(key, sections).and_then(|it| it.into_iter().next())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn names() {
+        assert_eq!(
+            Repository::Ucrt64.qualified_name("gcc"),
+            "mingw-w64-ucrt-x86_64-gcc"
+        );
+        assert_eq!(
+            Repository::Mingw64.qualified_name("mingw-w64-x86_64-gcc"),
+            "mingw-w64-x86_64-gcc"
+        );
+        assert_eq!(Repository::Msys.qualified_name("bash"), "bash");
+        assert_eq!(
+            Repository::Clang64.short_name("mingw-w64-clang-x86_64-zlib"),
+            "zlib"
+        );
+        assert_eq!(
+            Repository::Clang64.short_name("mingw-w64-x86_64-zlib"),
+            "mingw-w64-x86_64-zlib"
+        );
+        assert_eq!(Repository::environment("ucrt64"), Some(&Repository::Ucrt64));
+        assert!(Repository::environment("msys").is_none());
+        assert!(Repository::environment("unknown").is_none());
+    }
+
+    #[test]
+    fn read_description() {
+        let desc = "%FILENAME%\nbash-5.1.008-1-x86_64.pkg.tar.zst\n\n%NAME%\nbash\n\n\
+                    %BASE%\nbash\n\n%VERSION%\n5.1.008-1\n\n%DESC%\nThe GNU Bourne Again shell\n\n\
+                    %CSIZE%\n2370386\n\n%ISIZE%\n9547839\n\n%URL%\nhttps://www.gnu.org/software/bash/\n\n\
+                    %LICENSE%\nGPL\n\n%ARCH%\nx86_64\n\n%BUILDDATE%\n1631090565\n\n\
+                    %PACKAGER%\nCI (msys2/msys2-autobuild/1)\n\n%GROUPS%\nbase\n\n\
+                    %PROVIDES%\nsh\n\n%DEPENDS%\nmsys2-runtime\n\n\
+                    %OPTDEPENDS%\nbash-completion: for tab completion\n\n";
+        let package = Repository::Msys.read_description(desc).unwrap();
+        assert_eq!(package.name(), "bash");
+        assert_eq!(package.names, vec!["bash", "sh"]);
+        assert_eq!(
+            package.optional_dependencies,
+            vec!["bash-completion: for tab completion"]
+        );
+        assert_eq!(package.version, "5.1.008-1");
+        assert_eq!(package.compression, Some(&Compression::ZSTD));
+        let metadata = &package.metadata;
+        assert_eq!(
+            metadata.description.as_deref(),
+            Some("The GNU Bourne Again shell")
+        );
+        assert_eq!(
+            metadata.homepage.as_deref(),
+            Some("https://www.gnu.org/software/bash/")
+        );
+        assert_eq!(metadata.licenses, vec!["GPL"]);
+        assert_eq!(metadata.compressed_size, Some(2370386));
+        assert_eq!(metadata.installed_size, Some(9547839));
+        assert_eq!(metadata.build_date, Some(1631090565));
+        assert_eq!(
+            metadata.packager.as_deref(),
+            Some("CI (msys2/msys2-autobuild/1)")
+        );
+        assert_eq!(metadata.groups, vec!["base"]);
+        assert_eq!(metadata.base.as_deref(), Some("bash"));
+        assert!(package.installation.is_none());
+    }
+
+    #[test]
+    fn environments() {
+        assert_eq!(
+            Repository::environments()
+                .map(|it| it.name())
+                .collect::<Vec<_>>(),
+            vec!["mingw64", "clang64", "ucrt64"]
+        );
+        assert_eq!(Repository::environment("ucrt64"), Some(&Repository::Ucrt64));
+        assert!(Repository::environment("msys").is_none());
+        assert_eq!(Repository::from("clang64"), Some(&Repository::Clang64));
+    }
+
     #[test]
     fn test() {
         let packages = Repository::Msys.remote_packages().unwrap();
@@ -0,0 +1,206 @@
+use crate::commands::catalogue::Catalogue;
+use crate::commands::dependencies;
+use crate::commands::packages::Package;
+use std::collections::{BTreeMap, VecDeque};
+
+/// Index of the packages that depend on each package of a set
+/// (the installed packages or the available packages).
+/// Dependencies are resolved like the dependency resolver does: version constraints are ignored
+/// and a dependency resolves to the latest version of the packages with that name or providing it.
+pub struct ReverseDependencies<'a> {
+    dependents: BTreeMap<&'a Package, Vec<&'a Package>>,
+}
+
+impl<'a> ReverseDependencies<'a> {
+    pub fn new(packages: &'a Catalogue) -> Self {
+        let mut dependents: BTreeMap<&'a Package, Vec<&'a Package>> = BTreeMap::new();
+        for package in packages {
+            for dependency in package.dependencies.iter().flatten() {
+                let dependency = dependencies::dependency_name(dependency)
+                    .and_then(|name| packages.latest_version(name));
+                if let Some(dependency) = dependency {
+                    let list = dependents.entry(dependency).or_default();
+                    if !list.contains(&package) {
+                        list.push(package);
+                    }
+                }
+            }
+        }
+        Self { dependents }
+    }
+
+    /// Returns the packages that depend directly on the package.
+    pub fn dependents(&self, package: &Package) -> &[&'a Package] {
+        self.dependents
+            .get(package)
+            .map(|it| it.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Returns the packages that depend directly or indirectly on the package.
+    pub fn all_dependents(&self, package: &'a Package) -> Vec<&'a Package> {
+        let mut result: Vec<&'a Package> = Vec::new();
+        let mut queue: VecDeque<&'a Package> = VecDeque::from(vec![package]);
+        while let Some(next) = queue.pop_front() {
+            for &dependent in self.dependents(next) {
+                if dependent != package && !result.contains(&dependent) {
+                    result.push(dependent);
+                    queue.push_back(dependent);
+                }
+            }
+        }
+        result
+    }
+
+    /// Returns the shortest chain of dependents that leads from the package to each explicitly
+    /// installed package. Each chain starts with the package and ends with the explicit package.
+    /// The chains don't go through explicit packages.
+    pub fn why(&self, package: &'a Package) -> Vec<Vec<&'a Package>> {
+        // breadth-first search, with the dependency that led to each dependent
+        let mut parents: BTreeMap<&'a Package, &'a Package> = BTreeMap::new();
+        let mut explicit: Vec<&'a Package> = Vec::new();
+        let mut queue: VecDeque<&'a Package> = VecDeque::from(vec![package]);
+        while let Some(next) = queue.pop_front() {
+            if next.explicit() {
+                explicit.push(next);
+                continue;
+            }
+            for &dependent in self.dependents(next) {
+                // cyclic dependencies
+                if dependent != package && !parents.contains_key(dependent) {
+                    parents.insert(dependent, next);
+                    queue.push_back(dependent);
+                }
+            }
+        }
+        explicit
+            .into_iter()
+            .map(|end| {
+                let mut chain = vec![end];
+                while let Some(&parent) = parents.get(chain.last().unwrap()) {
+                    chain.push(parent);
+                }
+                chain.reverse();
+                chain
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use std::convert::TryFrom;
+
+    fn packages(lines: &[&str]) -> Catalogue {
+        Catalogue::from(
+            lines
+                .iter()
+                .map(|&it| Package::try_from(it).unwrap())
+                .collect::<BTreeSet<_>>(),
+        )
+    }
+
+    #[test]
+    fn provides_and_versions() {
+        let packages = packages(&[
+            "repository=msys\tname=bash\tprovides=sh\tversion=5.1-1",
+            "repository=msys\tname=make\tversion=4.3-1\tdepends=sh",
+            "repository=msys\tname=zlib\tversion=1.2-1",
+            "repository=msys\tname=zlib\tversion=1.3-1",
+            "repository=msys\tname=libxml2\tversion=1\tdepends=zlib>=1.3",
+        ]);
+        let index = ReverseDependencies::new(&packages);
+        let find = |name: &str, version: &str| {
+            packages
+                .iter()
+                .find(|it| it.name() == name && it.version == version)
+                .unwrap()
+        };
+        assert_eq!(index.dependents(find("bash", "5.1-1")).len(), 1);
+        assert_eq!(index.dependents(find("zlib", "1.3-1")).len(), 1);
+        assert!(index.dependents(find("zlib", "1.2-1")).is_empty());
+    }
+
+    #[test]
+    fn chains() {
+        let packages = packages(&[
+            "repository=msys\tname=python\tversion=1\tdepends=libxml2,zlib\tinstalldate=1\treason=explicit",
+            "repository=msys\tname=libxml2\tversion=1\tdepends=zlib>=1.0,libiconv\tinstalldate=1\treason=dependency",
+            "repository=msys\tname=zlib\tversion=1\tinstalldate=1\treason=dependency",
+            "repository=msys\tname=libiconv\tversion=1\tdepends=libintl\tinstalldate=1\treason=dependency",
+            "repository=msys\tname=libintl\tversion=1\tdepends=libiconv\tinstalldate=1\treason=dependency",
+            "repository=msys\tname=orphan\tversion=1\tinstalldate=1\treason=dependency",
+        ]);
+        let index = ReverseDependencies::new(&packages);
+        let find = |name: &str| packages.iter().find(|it| it.name() == name).unwrap();
+        let names = |chains: Vec<Vec<&Package>>| -> Vec<Vec<String>> {
+            chains
+                .iter()
+                .map(|chain| chain.iter().map(|it| it.name().to_string()).collect())
+                .collect()
+        };
+        let dependents: Vec<_> = index
+            .dependents(find("zlib"))
+            .iter()
+            .map(|it| it.name())
+            .collect();
+        assert_eq!(dependents, vec!["libxml2", "python"]);
+        assert_eq!(names(index.why(find("zlib"))), vec![vec!["zlib", "python"]]);
+        assert_eq!(
+            names(index.why(find("libintl"))),
+            vec![vec!["libintl", "libiconv", "libxml2", "python"]]
+        );
+        assert_eq!(names(index.why(find("python"))), vec![vec!["python"]]);
+        let dependents: Vec<_> = index
+            .all_dependents(find("libintl"))
+            .iter()
+            .map(|it| it.name())
+            .collect();
+        assert_eq!(dependents, vec!["libiconv", "libxml2", "python"]);
+        assert!(index.why(find("orphan")).is_empty());
+    }
+
+    #[test]
+    fn diamonds() {
+        // each level depends on both packages of the level below:
+        // the number of paths doubles with each level, but there is one chain per explicit package
+        let mut lines = vec![
+            "repository=msys\tname=a0\tversion=1\tinstalldate=1\treason=dependency".to_string(),
+            "repository=msys\tname=b0\tversion=1\tinstalldate=1\treason=dependency".to_string(),
+        ];
+        for level in 1..40 {
+            for name in ["a", "b"] {
+                lines.push(format!(
+                    "repository=msys\tname={}{}\tversion=1\tdepends=a{},b{}\tinstalldate=1\treason=dependency",
+                    name,
+                    level,
+                    level - 1,
+                    level - 1
+                ));
+            }
+        }
+        lines.push(
+            "repository=msys\tname=app\tversion=1\tdepends=a39,b39\tinstalldate=1\treason=explicit"
+                .to_string(),
+        );
+        lines.push(
+            "repository=msys\tname=tool\tversion=1\tdepends=b20\tinstalldate=1\treason=explicit"
+                .to_string(),
+        );
+        let lines: Vec<&str> = lines.iter().map(|it| it.as_str()).collect();
+        let packages = packages(&lines);
+        let index = ReverseDependencies::new(&packages);
+        let a0 = packages.iter().find(|it| it.name() == "a0").unwrap();
+        let chains = index.why(a0);
+        assert_eq!(chains.len(), 2);
+        assert_eq!(chains[0].len(), 22);
+        assert_eq!(chains[0].last().unwrap().name(), "tool");
+        assert_eq!(chains[1].len(), 41);
+        assert_eq!(chains[1].last().unwrap().name(), "app");
+        assert!(chains.iter().all(|chain| chain
+            .windows(2)
+            .all(|it| index.dependents(it[0]).contains(&it[1]))));
+    }
+}
@@ -0,0 +1,372 @@
+use crate::commands::errors::{Error, Result};
+use crate::commands::paths;
+use crate::commands::transaction::Transaction;
+use ansi_term::Color;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Saves the install script (.INSTALL) of a package, or removes the previous one if the package
+/// doesn't have one anymore. The script is kept until the package is removed, so that its
+/// functions can run when the package is upgraded or removed.
+pub fn save(
+    transaction: &mut Transaction,
+    root_directory_path: &Path,
+    name: &str,
+    script: Option<&[u8]>,
+) -> Result<()> {
+    let path = paths::get_package_script_file_path(root_directory_path, name);
+    match script {
+        Some(script) => {
+            transaction.record(&path)?;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, script)?;
+        }
+        None if path.exists() => {
+            transaction.record(&path)?;
+            fs::remove_file(&path)?;
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+/// Maximum duration of an install script function or a hook.
+const TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Runs the install scripts and the hooks with a shell (-c command).
+/// The default runner uses the bash of the installation root, but any shell can be used
+/// (e.g. /bin/sh for tests).
+pub struct Runner {
+    pub interpreter: PathBuf,
+    /// Variables added to the environment of the process (e.g. PATH and MSYSTEM).
+    pub environment: BTreeMap<String, String>,
+    pub working_directory: PathBuf,
+    /// The process is killed when it runs longer.
+    pub timeout: Duration,
+}
+
+/// What a command printed, and how it ended.
+#[derive(Debug)]
+pub struct Output {
+    pub stdout: String,
+    pub stderr: String,
+    /// None if the process was killed (e.g. after the timeout).
+    pub status: Option<i32>,
+    pub timed_out: bool,
+}
+
+impl Output {
+    pub fn success(&self) -> bool {
+        self.status == Some(0)
+    }
+
+    /// Prints the output of the command, stderr in red.
+    pub fn print(&self, description: &str) {
+        if !self.stdout.trim().is_empty() {
+            println!("{}", self.stdout.trim_end());
+        }
+        if !self.stderr.is_empty() {
+            println!("{}", Color::Red.paint(&self.stderr));
+        }
+        if self.timed_out {
+            println!(
+                "{}",
+                Color::Red.paint(format!("{} timed out.", description))
+            );
+        }
+    }
+}
+
+impl Runner {
+    /// The bash of the root directory, with the msys environment, from the root directory.
+    pub fn new(root_directory_path: &Path) -> Self {
+        let bin = root_directory_path.join("usr").join("bin");
+        let mut environment = BTreeMap::new();
+        environment.insert("PATH".to_string(), bin.to_string_lossy().to_string());
+        environment.insert("MSYSTEM".to_string(), "MSYS".to_string());
+        Self {
+            interpreter: bin.join("bash.exe"),
+            environment,
+            working_directory: root_directory_path.to_path_buf(),
+            timeout: TIMEOUT,
+        }
+    }
+
+    /// Runs a shell command with the arguments as positional parameters, and the input on stdin
+    /// if there is one. Returns an error if the interpreter couldn't be started.
+    pub fn run(&self, command: &str, args: &[&str], input: Option<&str>) -> Result<Output> {
+        let mut child = Command::new(&self.interpreter)
+            .arg("-c")
+            .arg(command)
+            // $0
+            .arg("sh")
+            .args(args)
+            .envs(&self.environment)
+            .current_dir(&self.working_directory)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // the pipes are read from other threads so that a full pipe doesn't block the process
+        let stdout = read_in_background(child.stdout.take());
+        let stderr = read_in_background(child.stderr.take());
+        // and the input is written from another thread, so that the timeout also applies
+        // while the process doesn't read it (stdin is closed when it is dropped)
+        if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+            let input = input.to_string();
+            thread::spawn(move || {
+                let _ = stdin.write_all(input.as_bytes());
+            });
+        }
+        let start = Instant::now();
+        let (status, timed_out) = loop {
+            if let Some(status) = child.try_wait()? {
+                break (status.code(), false);
+            }
+            if start.elapsed() >= self.timeout {
+                let _ = child.kill();
+                let _ = child.wait();
+                break (None, true);
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        // processes started by the command can keep the pipes open after a timeout
+        let wait = if timed_out {
+            Duration::from_millis(100)
+        } else {
+            Duration::from_secs(1)
+        };
+        Ok(Output {
+            stdout: stdout.recv_timeout(wait).unwrap_or_default(),
+            stderr: stderr.recv_timeout(wait).unwrap_or_default(),
+            status,
+            timed_out,
+        })
+    }
+
+    /// Runs a function of the install script of a package, if it has a script defining it.
+    /// The arguments are the same as pacman's:
+    /// pre_install and post_install get the new version,
+    /// pre_upgrade and post_upgrade get the new version and the old version,
+    /// pre_remove and post_remove get the old version.
+    /// The function is skipped with a warning if the interpreter is not installed yet.
+    pub fn run_function(
+        &self,
+        root_directory_path: &Path,
+        name: &str,
+        function: &str,
+        versions: &[&str],
+    ) -> Result<()> {
+        let path = paths::get_package_script_file_path(root_directory_path, name);
+        if !path.exists() {
+            return Ok(());
+        }
+        // the script is sourced with a path that works for any shell
+        let script = format!(
+            ". '{}' && if command -v {} >/dev/null; then {} \"$@\"; fi",
+            path.to_string_lossy().replace('\\', "/"),
+            function,
+            function
+        );
+        let description = format!("The {} function of {}", function, name);
+        match self.run(&script, versions, None) {
+            Ok(output) => {
+                output.print(&description);
+                if output.success() {
+                    Ok(())
+                } else {
+                    println!("{}", Color::Red.paint(format!("{} failed.", description)));
+                    Err(Error::ScriptFailed(function.to_string()))
+                }
+            }
+            // the shell is not installed yet (packages installed before bash)
+            Err(_) if !self.interpreter.exists() => {
+                println!(
+                    "{}",
+                    Color::Yellow.paint(format!(
+                        "Skipping the {} function of {}: {} is not installed yet.",
+                        function,
+                        name,
+                        self.interpreter.to_string_lossy()
+                    ))
+                );
+                Ok(())
+            }
+            Err(err) => {
+                println!("{}", Color::Red.paint("Failed to run install script"));
+                println!("{:?}", err);
+                Err(err)
+            }
+        }
+    }
+}
+
+fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>) -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    if let Some(mut pipe) = pipe {
+        thread::spawn(move || {
+            let mut bytes = Vec::new();
+            let _ = pipe.read_to_end(&mut bytes);
+            let _ = sender.send(String::from_utf8_lossy(&bytes).to_string());
+        });
+    }
+    receiver
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    lazy_static! {
+        static ref DATA_DIR: PathBuf = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("data");
+    }
+
+    const SCRIPT: &str = r#"
+pre_install() {
+  echo "pre_install $1" >> calls.log
+}
+post_upgrade() {
+  echo "post_upgrade $1 $2" >> calls.log
+}
+pre_remove() {
+  echo "pre_remove $1" >> calls.log
+  return 1
+}
+"#;
+
+    /// Runs the commands with the system shell instead of the bash of the root directory.
+    pub(crate) fn runner(root_directory_path: &Path) -> Runner {
+        let mut runner = Runner {
+            interpreter: PathBuf::from("/bin/sh"),
+            ..Runner::new(root_directory_path)
+        };
+        runner
+            .environment
+            .insert("PATH".to_string(), "/usr/bin:/bin".to_string());
+        runner
+    }
+
+    #[test]
+    fn run() {
+        let root_directory_path = DATA_DIR.join("tmp_runner");
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+        fs::create_dir_all(&root_directory_path).unwrap();
+        let mut runner = runner(&root_directory_path);
+        let output = runner
+            .run(
+                "echo \"$MSYSTEM $1\"; pwd; cat; echo error >&2; exit 3",
+                &["arg"],
+                Some("input"),
+            )
+            .unwrap();
+        assert_eq!(
+            output.stdout,
+            format!(
+                "MSYS arg\n{}\ninput",
+                root_directory_path
+                    .canonicalize()
+                    .unwrap()
+                    .to_string_lossy()
+            )
+        );
+        assert_eq!(output.stderr, "error\n");
+        assert_eq!(output.status, Some(3));
+        assert!(!output.success());
+        assert!(!output.timed_out);
+
+        // more input and output than the pipes can hold, with output before the input is read
+        let input = "line\n".repeat(200_000);
+        let output = runner
+            .run("head -c 200000 /dev/zero; wc -l", &[], Some(&input))
+            .unwrap();
+        assert!(output.success());
+        assert_eq!(output.stdout.len(), 200_000 + "200000\n".len());
+        assert_eq!(output.stdout[200_000..].trim(), "200000");
+
+        runner.timeout = Duration::from_millis(200);
+        let start = Instant::now();
+        let output = runner.run("sleep 5; echo done", &[], None).unwrap();
+        assert!(output.timed_out);
+        assert!(!output.success());
+        assert!(start.elapsed() < Duration::from_secs(4));
+        // input that is never read
+        let start = Instant::now();
+        let output = runner.run("sleep 5", &[], Some(&input)).unwrap();
+        assert!(output.timed_out);
+        assert!(start.elapsed() < Duration::from_secs(4));
+
+        runner.interpreter = root_directory_path.join("missing");
+        assert!(runner.run("true", &[], None).is_err());
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+    }
+
+    #[test]
+    fn functions() {
+        let root_directory_path = DATA_DIR.join("tmp_scripts");
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+        fs::create_dir_all(&root_directory_path).unwrap();
+        let runner = runner(&root_directory_path);
+        let run = |function: &str, versions: &[&str]| {
+            runner.run_function(&root_directory_path, "test", function, versions)
+        };
+        // no script
+        assert!(run("pre_install", &["1.0-1"]).is_ok());
+
+        let mut transaction = Transaction::begin(&root_directory_path).unwrap();
+        save(
+            &mut transaction,
+            &root_directory_path,
+            "test",
+            Some(SCRIPT.as_bytes()),
+        )
+        .unwrap();
+        transaction.commit().unwrap();
+        assert!(run("pre_install", &["1.0-1"]).is_ok());
+        // functions that are not defined are skipped
+        assert!(run("post_install", &["1.0-1"]).is_ok());
+        assert!(run("post_upgrade", &["1.1-1", "1.0-1"]).is_ok());
+        assert!(matches!(
+            run("pre_remove", &["1.1-1"]),
+            Err(Error::ScriptFailed(function)) if function == "pre_remove"
+        ));
+        assert_eq!(
+            fs::read_to_string(root_directory_path.join("calls.log")).unwrap(),
+            "pre_install 1.0-1\npost_upgrade 1.1-1 1.0-1\npre_remove 1.1-1\n"
+        );
+        // the shell is not installed yet
+        let missing = Runner {
+            interpreter: root_directory_path.join("missing"),
+            ..self::runner(&root_directory_path)
+        };
+        assert!(missing
+            .run_function(&root_directory_path, "test", "pre_install", &["1.0-1"])
+            .is_ok());
+        // the shell can't be started
+        let invalid = Runner {
+            interpreter: root_directory_path.clone(),
+            ..self::runner(&root_directory_path)
+        };
+        assert!(matches!(
+            invalid.run_function(&root_directory_path, "test", "pre_install", &["1.0-1"]),
+            Err(Error::IOError(_))
+        ));
+
+        // the previous script is restored on rollback
+        let mut transaction = Transaction::begin(&root_directory_path).unwrap();
+        save(&mut transaction, &root_directory_path, "test", None).unwrap();
+        assert!(!paths::get_package_script_file_path(&root_directory_path, "test").exists());
+        transaction.rollback().unwrap();
+        assert!(paths::get_package_script_file_path(&root_directory_path, "test").exists());
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+    }
+}
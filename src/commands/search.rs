@@ -0,0 +1,234 @@
+use crate::commands::errors::{Error, Result};
+use crate::commands::packages::Package;
+use ansi_term::Color;
+use regex::{Captures, Regex};
+use std::cmp::Ordering;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TermKind {
+    /// Plain text, matched as a substring of the name (or of the description).
+    Text,
+    /// Glob pattern (*, ?, [...]), matched against the whole name (or part of the description).
+    Glob,
+    /// Regular expression, matched against the name or the description.
+    Regex,
+}
+
+/// A search term and the regular expressions used to match package names and descriptions.
+#[derive(Debug)]
+pub struct Term<'a> {
+    pub text: &'a str,
+    pub kind: TermKind,
+    name: Regex,
+    description: Regex,
+}
+
+impl<'a> Term<'a> {
+    /// Terms containing glob special characters are treated as glob patterns,
+    /// unless regex is true, in which case all terms are regular expressions.
+    pub fn parse(text: &'a str, regex: bool) -> Result<Self> {
+        let (kind, name, description) = if regex {
+            (TermKind::Regex, text.to_string(), text.to_string())
+        } else if text.contains(['*', '?', '[']) {
+            let pattern = glob_to_regex(text);
+            (
+                TermKind::Glob,
+                format!("^{}$", pattern),
+                format!("(?i){}", pattern),
+            )
+        } else {
+            let pattern = regex::escape(text);
+            (TermKind::Text, pattern.clone(), format!("(?i){}", pattern))
+        };
+        Ok(Self {
+            text,
+            kind,
+            name: Regex::new(&name).map_err(|_| Error::ParseError)?,
+            description: Regex::new(&description).map_err(|_| Error::ParseError)?,
+        })
+    }
+    /// Name matches score higher than description matches.
+    /// For plain text terms, exact and prefix name matches score higher than other name matches.
+    /// Names are matched with and without the repository package prefix.
+    pub fn score(&self, package: &Package) -> u16 {
+        let name_score = self
+            .name_score(package.name())
+            .max(self.name_score(package.short_name()));
+        if name_score > 0 {
+            name_score
+        } else if package
+            .metadata
+            .description
+            .as_ref()
+            .map(|it| self.description.is_match(it))
+            .unwrap_or(false)
+        {
+            1
+        } else {
+            0
+        }
+    }
+    fn name_score(&self, name: &str) -> u16 {
+        match self.kind {
+            TermKind::Text => {
+                if name == self.text {
+                    16
+                } else if name.starts_with(self.text) {
+                    8
+                } else if name.starts_with(format!("lib{}", self.text).as_str()) {
+                    4
+                } else if name.contains(self.text) {
+                    2
+                } else {
+                    0
+                }
+            }
+            _ => {
+                if self.name.is_match(name) {
+                    2
+                } else {
+                    0
+                }
+            }
+        }
+    }
+    /// Colors the parts of the name matched by this term.
+    pub fn highlight(&self, name: &str) -> String {
+        self.name
+            .replace_all(name, |captures: &Captures| {
+                Color::Green.paint(&captures[0]).to_string()
+            })
+            .to_string()
+    }
+}
+
+/// Returns the packages matching at least one of the terms, best matches first.
+pub fn search<'a, I>(packages: I, terms: &[Term]) -> Vec<(u16, &'a Package)>
+where
+    I: Iterator<Item = &'a Package>,
+{
+    let mut results: Vec<_> = packages
+        .filter_map(|package| {
+            let score: u16 = terms.iter().map(|term| term.score(package)).sum();
+            if score == 0 {
+                None
+            } else {
+                Some((score, package))
+            }
+        })
+        .collect();
+    results.sort_by(|a, b| match a.0.cmp(&b.0) {
+        Ordering::Equal => a.1.cmp(b.1).reverse(),
+        it => it.reverse(),
+    });
+    results
+}
+
+/// Converts a glob pattern (*, ?, [...] and [!...]) to an unanchored regular expression.
+pub fn glob_to_regex(glob: &str) -> String {
+    let mut pattern = String::with_capacity(glob.len() * 2);
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            '[' => {
+                let mut class = String::new();
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    class.push('^');
+                }
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        closed = true;
+                        break;
+                    }
+                    if c == '\\' || c == '[' || (c == '^' && class.is_empty()) {
+                        class.push('\\');
+                    }
+                    class.push(c);
+                }
+                if closed {
+                    pattern.push('[');
+                    pattern.push_str(&class);
+                    pattern.push(']');
+                } else {
+                    pattern.push_str(&regex::escape(&format!("[{}", class)));
+                }
+            }
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn package(name: &str, description: &str) -> Package {
+        Package::try_from(
+            format!(
+                "repository=mingw64\tname={}\tversion=1.0\tdescription={}",
+                name, description
+            )
+            .as_str(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn globs() {
+        assert_eq!(glob_to_regex("zlib*"), "zlib.*");
+        assert_eq!(glob_to_regex("a?c"), "a.c");
+        assert_eq!(glob_to_regex("lib[xy]ml"), "lib[xy]ml");
+        assert_eq!(glob_to_regex("[!a]"), "[^a]");
+        assert_eq!(glob_to_regex("a.b+"), "a\\.b\\+");
+        assert_eq!(glob_to_regex("[abc"), "\\[abc");
+    }
+
+    #[test]
+    fn scores() {
+        let zlib = package("mingw-w64-x86_64-zlib", "Compression library");
+        let pkgconf = package(
+            "mingw-w64-x86_64-pkgconf",
+            "pkg-config compatible utility which does not depend on glib",
+        );
+        let term = Term::parse("zlib", false).unwrap();
+        assert_eq!(term.kind, TermKind::Text);
+        assert_eq!(term.score(&zlib), 16);
+        let term = Term::parse("x86_64-zlib", false).unwrap();
+        assert_eq!(term.score(&zlib), 2);
+        assert_eq!(term.score(&pkgconf), 0);
+        let term = Term::parse("PKG-CONFIG", false).unwrap();
+        assert_eq!(term.score(&pkgconf), 1);
+        let term = Term::parse("mingw-w64-x86_64-z*", false).unwrap();
+        assert_eq!(term.kind, TermKind::Glob);
+        assert_eq!(term.score(&zlib), 2);
+        assert_eq!(term.score(&pkgconf), 0);
+        let term = Term::parse("pkg*", false).unwrap();
+        assert_eq!(term.score(&pkgconf), 2);
+        let term = Term::parse("*compatible*", false).unwrap();
+        assert_eq!(term.score(&pkgconf), 1);
+        let term = Term::parse("pkg(conf|-config)$", true).unwrap();
+        assert_eq!(term.kind, TermKind::Regex);
+        assert_eq!(term.score(&pkgconf), 2);
+        assert!(Term::parse("(", true).is_err());
+    }
+
+    #[test]
+    fn results() {
+        let packages = [
+            package("libxml2", "XML parsing library"),
+            package("xml", "something else"),
+            package("other", "uses xml"),
+            package("unrelated", "nothing"),
+        ];
+        let terms = vec![Term::parse("xml", false).unwrap()];
+        let results = search(packages.iter(), &terms);
+        let names: Vec<_> = results.iter().map(|it| it.1.name()).collect();
+        assert_eq!(names, vec!["xml", "libxml2", "other"]);
+    }
+}
@@ -0,0 +1,324 @@
+use crate::commands::errors::{Error, Result};
+use crate::commands::format;
+use crate::commands::lock;
+use crate::commands::packages::Package;
+use crate::commands::paths;
+use std::collections::BTreeSet;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const JOURNAL: &str = "journal";
+const STAGING: &str = "staging";
+const BACKUP: &str = "backup";
+/// The path did not exist before the transaction and needs to be removed on rollback.
+const CREATED_KEY: &str = "created";
+/// The file existed before the transaction and a copy was saved in the backup directory.
+const BACKED_UP_KEY: &str = "backup";
+/// The junction (or directory symlink) existed before the transaction, with the given target.
+const LINKED_KEY: &str = "linked";
+/// The directory existed before the transaction and may have been removed.
+const DIRECTORY_KEY: &str = "directory";
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// True between the beginning of a transaction and its commit or rollback.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Changes to the installation root that can be rolled back.
+/// Every path is recorded in the journal before it is modified: new paths are flagged as created,
+/// and existing files are copied to the backup directory first.
+/// The journal, the backups and the staged package archives are kept in
+/// var/local/packages/transaction until the transaction is committed or rolled back,
+/// so that an interrupted transaction can still be rolled back later (recover command).
+pub struct Transaction {
+    root_directory_path: PathBuf,
+    directory: PathBuf,
+    journal: File,
+    recorded: BTreeSet<PathBuf>,
+}
+
+impl Transaction {
+    /// Starts a new transaction. Until it is committed or rolled back, Ctrl-C no longer stops the
+    /// program immediately but flags the transaction as interrupted so that it can be rolled back.
+    pub fn begin(root_directory_path: &Path) -> Result<Self> {
+        let directory = paths::get_transaction_directory_path(root_directory_path);
+        if directory.exists() {
+            // a previous transaction needs to be recovered first
+            return Err(Error::IOError(std::io::Error::from(
+                std::io::ErrorKind::AlreadyExists,
+            )));
+        }
+        fs::create_dir_all(directory.join(STAGING))?;
+        fs::create_dir_all(directory.join(BACKUP))?;
+        let journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(directory.join(JOURNAL))?;
+        ACTIVE.store(true, Ordering::SeqCst);
+        let _ = ctrlc::set_handler(|| {
+            if ACTIVE.load(Ordering::SeqCst) {
+                INTERRUPTED.store(true, Ordering::SeqCst);
+            } else {
+                lock::exit(130);
+            }
+        });
+        let mut transaction = Self {
+            root_directory_path: root_directory_path.to_path_buf(),
+            directory,
+            journal,
+            recorded: BTreeSet::new(),
+        };
+        transaction.record(&paths::get_installed_packages_file_path(
+            root_directory_path,
+        ))?;
+        transaction.record(&paths::get_installed_packages_backup_file_path(
+            root_directory_path,
+        ))?;
+        Ok(transaction)
+    }
+
+    /// Saves the uncompressed package archive until the transaction ends.
+    pub fn stage(&self, package: &Package, uncompressed_package_archive: &[u8]) -> Result<()> {
+        fs::write(self.staged_path(package), uncompressed_package_archive)?;
+        Ok(())
+    }
+
+    /// Returns the uncompressed package archive saved with stage.
+    pub fn staged(&self, package: &Package) -> Result<Vec<u8>> {
+        Ok(fs::read(self.staged_path(package))?)
+    }
+
+    fn staged_path(&self, package: &Package) -> PathBuf {
+        self.directory
+            .join(STAGING)
+            .join(format!("{}-{}.tar", package.name(), package.version))
+    }
+
+    /// Records the previous state of a path under the root directory before it is modified.
+    /// Missing parent directories are recorded as created.
+    /// Existing directories are not recorded, because they are shared between packages.
+    pub fn record(&mut self, path: &Path) -> Result<()> {
+        let relative_path = path
+            .strip_prefix(&self.root_directory_path)
+            .map_err(|_| Error::ParseError)?
+            .to_path_buf();
+        if self.recorded.contains(&relative_path) {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            if parent != self.root_directory_path && fs::symlink_metadata(parent).is_err() {
+                self.record(parent)?;
+            }
+        }
+        self.recorded.insert(relative_path.clone());
+        let line = match fs::symlink_metadata(path) {
+            Err(_) => format::field(CREATED_KEY, &relative_path.to_string_lossy()),
+            Ok(metadata) if metadata.is_dir() => return Ok(()),
+            Ok(_) if path.is_dir() => {
+                let target = junction::get_target(path)?;
+                format::list_field(
+                    LINKED_KEY,
+                    &[
+                        relative_path.to_string_lossy().to_string(),
+                        target.to_string_lossy().to_string(),
+                    ],
+                )
+            }
+            Ok(_) => {
+                let backup_path = self.directory.join(BACKUP).join(&relative_path);
+                if let Some(parent) = backup_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::copy(path, &backup_path)?;
+                format::field(BACKED_UP_KEY, &relative_path.to_string_lossy())
+            }
+        };
+        writeln!(self.journal, "{}", line)?;
+        self.journal.flush()?;
+        Ok(())
+    }
+
+    /// Records an existing directory before it is removed, so that it is created again on
+    /// rollback. Directories are only removed when they are empty, so only the directory itself
+    /// needs to be restored.
+    pub fn record_removed_directory(&mut self, path: &Path) -> Result<()> {
+        let relative_path = path
+            .strip_prefix(&self.root_directory_path)
+            .map_err(|_| Error::ParseError)?;
+        writeln!(
+            self.journal,
+            "{}",
+            format::field(DIRECTORY_KEY, &relative_path.to_string_lossy())
+        )?;
+        self.journal.flush()?;
+        Ok(())
+    }
+
+    /// Keeps the changes and removes the journal, the backups and the staged archives.
+    pub fn commit(self) -> Result<()> {
+        let result = rm_rf::remove(&self.directory).map_err(|_| Error::RemoveError);
+        ACTIVE.store(false, Ordering::SeqCst);
+        result
+    }
+
+    /// Restores the state of the root directory from before the transaction.
+    pub fn rollback(self) -> Result<()> {
+        drop(self.journal);
+        let result = rollback(&self.root_directory_path);
+        ACTIVE.store(false, Ordering::SeqCst);
+        result
+    }
+}
+
+/// Returns true if Ctrl-C was pressed during a transaction.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Returns true if a transaction was neither committed nor rolled back.
+pub fn exists(root_directory_path: &Path) -> bool {
+    paths::get_transaction_directory_path(root_directory_path).exists()
+}
+
+/// Rolls back the transaction found in the root directory.
+/// The journal is only removed once everything is restored, so the rollback can be restarted
+/// if it is interrupted.
+pub fn rollback(root_directory_path: &Path) -> Result<()> {
+    let directory = paths::get_transaction_directory_path(root_directory_path);
+    let journal = fs::read_to_string(directory.join(JOURNAL)).unwrap_or_default();
+    let mut failed = false;
+    // undo the changes in reverse order, so that files are removed before their directories
+    for line in journal.lines().rev() {
+        for (key, value) in format::fields(line) {
+            if key == LINKED_KEY {
+                if let [relative_path, target] = format::split_list(value).as_slice() {
+                    let path = root_directory_path.join(relative_path);
+                    if fs::symlink_metadata(&path).is_err() {
+                        failed |= junction::create(Path::new(target), &path).is_err();
+                    }
+                }
+                continue;
+            }
+            let relative_path = PathBuf::from(format::unescape(value));
+            let path = root_directory_path.join(&relative_path);
+            match key {
+                CREATED_KEY => match fs::symlink_metadata(&path) {
+                    // directories (and junctions) are only removed if they are empty
+                    Ok(metadata) if metadata.is_dir() => {
+                        let _ = fs::remove_dir(&path);
+                    }
+                    // junctions can only be removed as directories
+                    Ok(_) => {
+                        failed |= fs::remove_file(&path)
+                            .or_else(|_| fs::remove_dir(&path))
+                            .is_err()
+                    }
+                    Err(_) => {}
+                },
+                DIRECTORY_KEY => {
+                    failed |= fs::create_dir_all(&path).is_err();
+                }
+                BACKED_UP_KEY => {
+                    let backup_path = directory.join(BACKUP).join(&relative_path);
+                    if backup_path.exists() {
+                        // the parent directory may have been removed with the package
+                        if let Some(parent) = path.parent() {
+                            let _ = fs::create_dir_all(parent);
+                        }
+                        failed |= rm_rf::ensure_removed(&path).is_err()
+                            || fs::copy(&backup_path, &path).is_err();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    if failed {
+        Err(Error::RemoveError)
+    } else {
+        rm_rf::remove(&directory).map_err(|_| Error::RemoveError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    lazy_static! {
+        static ref DATA_DIR: PathBuf = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("data");
+    }
+
+    fn modify(transaction: &mut Transaction, path: &Path, content: &str) {
+        transaction.record(path).unwrap();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn rollback_and_recover() {
+        let root_directory_path = DATA_DIR.join("tmp_transaction");
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+        let existing = root_directory_path.join("usr").join("bin").join("existing");
+        fs::create_dir_all(existing.parent().unwrap()).unwrap();
+        fs::write(&existing, "before").unwrap();
+        let created = root_directory_path
+            .join("usr")
+            .join("share")
+            .join("a")
+            .join("new");
+
+        let mut transaction = Transaction::begin(&root_directory_path).unwrap();
+        assert!(exists(&root_directory_path));
+        assert!(Transaction::begin(&root_directory_path).is_err());
+        modify(&mut transaction, &existing, "after");
+        modify(&mut transaction, &existing, "after again");
+        modify(&mut transaction, &created, "new");
+        transaction.rollback().unwrap();
+        assert!(!exists(&root_directory_path));
+        assert_eq!(fs::read_to_string(&existing).unwrap(), "before");
+        assert!(!created.exists());
+        assert!(!root_directory_path.join("usr").join("share").exists());
+
+        // simulate a crash: the transaction is dropped without commit or rollback
+        let mut transaction = Transaction::begin(&root_directory_path).unwrap();
+        modify(&mut transaction, &existing, "after");
+        modify(&mut transaction, &created, "new");
+        drop(transaction);
+        assert!(exists(&root_directory_path));
+        rollback(&root_directory_path).unwrap();
+        assert_eq!(fs::read_to_string(&existing).unwrap(), "before");
+        assert!(!created.exists());
+
+        // removed directories are created again
+        let empty = root_directory_path.join("usr").join("lib");
+        fs::create_dir_all(&empty).unwrap();
+        let mut transaction = Transaction::begin(&root_directory_path).unwrap();
+        transaction.record_removed_directory(&empty).unwrap();
+        fs::remove_dir(&empty).unwrap();
+        transaction.rollback().unwrap();
+        assert!(empty.is_dir());
+
+        // junctions are restored with their target
+        #[cfg(windows)]
+        {
+            let link = root_directory_path.join("bin");
+            junction::create(existing.parent().unwrap(), &link).unwrap();
+            let mut transaction = Transaction::begin(&root_directory_path).unwrap();
+            transaction.record(&link).unwrap();
+            fs::remove_file(&link)
+                .or_else(|_| fs::remove_dir(&link))
+                .unwrap();
+            transaction.rollback().unwrap();
+            assert!(link.join("existing").exists());
+        }
+
+        let mut transaction = Transaction::begin(&root_directory_path).unwrap();
+        modify(&mut transaction, &existing, "after");
+        transaction.commit().unwrap();
+        assert!(!exists(&root_directory_path));
+        assert_eq!(fs::read_to_string(&existing).unwrap(), "after");
+        rm_rf::ensure_removed(&root_directory_path).unwrap();
+    }
+}
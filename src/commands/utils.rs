@@ -4,7 +4,7 @@ use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::fs;
 use std::io;
 use std::path::Path;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::commands::errors::Error::{DecompressionError, DownloadError};
 use crate::commands::errors::Result;
@@ -85,6 +85,55 @@ pub fn file_was_updated_recently(path: &Path) -> bool {
         .unwrap_or(false)
 }
 
+/// Returns the number of seconds elapsed since the unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|it| it.as_secs())
+        .unwrap_or(0)
+}
+
+/// Formats a unix timestamp (in seconds) as a UTC date: YYYY-MM-DD HH:MM:SS UTC.
+pub fn format_date(timestamp: u64) -> String {
+    let days = (timestamp / 86_400) as i64;
+    let seconds = timestamp % 86_400;
+    // civil from days (http://howardhinnant.github.io/date_algorithms.html)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3_600,
+        (seconds % 3_600) / 60,
+        seconds % 60
+    )
+}
+
+/// Formats a size in bytes with a binary unit (B, KiB, MiB, GiB).
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.2} {}", size, UNITS[unit])
+    }
+}
+
 /// A string representing the value of http response header: 'ETag'.
 #[derive(Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]
 pub struct ETag {
@@ -199,3 +248,23 @@ impl Display for Compression {
         f.write_str(self.extension())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates() {
+        assert_eq!(format_date(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_date(951_782_400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_date(1_640_995_199), "2021-12-31 23:59:59 UTC");
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.50 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024), "3.00 MiB");
+    }
+}
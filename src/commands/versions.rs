@@ -0,0 +1,159 @@
+use std::cmp::Ordering;
+
+/// Compares two package versions ([epoch:]version[-release]) the same way pacman does.
+pub fn compare(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+    let (epoch1, version1, release1) = parse(a);
+    let (epoch2, version2, release2) = parse(b);
+    compare_segments(epoch1, epoch2)
+        .then_with(|| compare_segments(version1, version2))
+        .then_with(|| match (release1, release2) {
+            (Some(release1), Some(release2)) => compare_segments(release1, release2),
+            _ => Ordering::Equal,
+        })
+}
+
+/// Splits a version into epoch, version and optional release.
+fn parse(value: &str) -> (&str, &str, Option<&str>) {
+    let digits = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (epoch, rest) = if value[digits..].starts_with(':') {
+        let epoch = &value[..digits];
+        (
+            if epoch.is_empty() { "0" } else { epoch },
+            &value[digits + 1..],
+        )
+    } else {
+        ("0", value)
+    };
+    match rest.rfind('-') {
+        Some(position) => (epoch, &rest[..position], Some(&rest[position + 1..])),
+        None => (epoch, rest, None),
+    }
+}
+
+/// Compares alternating numeric and alphabetic segments (rpmvercmp).
+fn compare_segments(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+    let one = a.as_bytes();
+    let two = b.as_bytes();
+    let (mut i, mut j) = (0, 0);
+    while i < one.len() && j < two.len() {
+        let (start1, start2) = (i, j);
+        while i < one.len() && !one[i].is_ascii_alphanumeric() {
+            i += 1;
+        }
+        while j < two.len() && !two[j].is_ascii_alphanumeric() {
+            j += 1;
+        }
+        if i >= one.len() || j >= two.len() {
+            break;
+        }
+        // different separator lengths
+        if i - start1 != j - start2 {
+            return (i - start1).cmp(&(j - start2));
+        }
+        let (segment_start1, segment_start2) = (i, j);
+        let numeric = one[i].is_ascii_digit();
+        if numeric {
+            while i < one.len() && one[i].is_ascii_digit() {
+                i += 1;
+            }
+            while j < two.len() && two[j].is_ascii_digit() {
+                j += 1;
+            }
+        } else {
+            while i < one.len() && one[i].is_ascii_alphabetic() {
+                i += 1;
+            }
+            while j < two.len() && two[j].is_ascii_alphabetic() {
+                j += 1;
+            }
+        }
+        let mut segment1 = &one[segment_start1..i];
+        let mut segment2 = &two[segment_start2..j];
+        // numeric segments are newer than alphabetic segments
+        if segment2.is_empty() {
+            return if numeric {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+        }
+        if numeric {
+            while segment1.first() == Some(&b'0') {
+                segment1 = &segment1[1..];
+            }
+            while segment2.first() == Some(&b'0') {
+                segment2 = &segment2[1..];
+            }
+            match segment1.len().cmp(&segment2.len()) {
+                Ordering::Equal => {}
+                it => return it,
+            }
+        }
+        match segment1.cmp(segment2) {
+            Ordering::Equal => {}
+            it => return it,
+        }
+    }
+    let rest1 = &one[i..];
+    let rest2 = &two[j..];
+    if rest1.is_empty() && rest2.is_empty() {
+        return Ordering::Equal;
+    }
+    // a remaining alphabetic segment never beats an empty one
+    if (rest1.is_empty() && !rest2[0].is_ascii_alphabetic())
+        || rest1
+            .first()
+            .map(|it| it.is_ascii_alphabetic())
+            .unwrap_or(false)
+    {
+        Ordering::Less
+    } else {
+        Ordering::Greater
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(a: &str, b: &str, expected: Ordering) {
+        assert_eq!(compare(a, b), expected, "{} vs {}", a, b);
+        assert_eq!(compare(b, a), expected.reverse(), "{} vs {}", b, a);
+    }
+
+    #[test]
+    fn versions() {
+        check("1.0", "1.0", Ordering::Equal);
+        check("1.0", "1.1", Ordering::Less);
+        check("1.9", "1.10", Ordering::Less);
+        check("1.0a", "1.0", Ordering::Less);
+        check("1.0alpha", "1.0beta", Ordering::Less);
+        check("1.0", "1.0.1", Ordering::Less);
+        check("1.0.a", "1.0", Ordering::Greater);
+        check("1.0.a", "1.0.1", Ordering::Less);
+        check("1.001", "1.1", Ordering::Equal);
+        check("1.0rc1", "1.0", Ordering::Less);
+        check("1.0+1", "1.0.1", Ordering::Equal);
+        check("1.0..1", "1.0.1", Ordering::Greater);
+    }
+
+    #[test]
+    fn releases_and_epochs() {
+        check("1.0-1", "1.0-2", Ordering::Less);
+        check("1.0-2", "1.1-1", Ordering::Less);
+        check("1.0", "1.0-2", Ordering::Equal);
+        check("1:1.0-1", "2.0-1", Ordering::Greater);
+        check("1:1.0", "2:0.1", Ordering::Less);
+        check("0:1.0", "1.0", Ordering::Equal);
+        check("11.2.0-4", "11.2.0-10", Ordering::Less);
+        check("5.1.008-1", "5.1.016-1", Ordering::Less);
+    }
+}